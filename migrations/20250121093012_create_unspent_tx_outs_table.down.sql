@@ -0,0 +1 @@
+DROP TABLE unspent_tx_outs;
@@ -0,0 +1,7 @@
+CREATE TABLE unspent_tx_outs (
+  tx_out_id CHAR(64) NOT NULL,
+  tx_out_index INTEGER NOT NULL,
+  address CHAR(64) NOT NULL,
+  amount INTEGER NOT NULL,
+  PRIMARY KEY (tx_out_id, tx_out_index)
+);
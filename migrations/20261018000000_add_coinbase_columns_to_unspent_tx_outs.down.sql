@@ -0,0 +1,2 @@
+ALTER TABLE unspent_tx_outs DROP COLUMN block_index;
+ALTER TABLE unspent_tx_outs DROP COLUMN is_coinbase;
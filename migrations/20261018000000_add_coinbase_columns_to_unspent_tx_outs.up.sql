@@ -0,0 +1,16 @@
+ALTER TABLE unspent_tx_outs ADD COLUMN is_coinbase BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE unspent_tx_outs ADD COLUMN block_index INTEGER NOT NULL DEFAULT 0;
+
+UPDATE unspent_tx_outs
+   SET is_coinbase = EXISTS (
+         SELECT 1
+           FROM tx_ins
+          WHERE tx_ins.transaction_id = unspent_tx_outs.tx_out_id
+            AND tx_ins.tx_out_id = ''
+       ),
+       block_index = COALESCE((
+         SELECT blocks.block_index
+           FROM transactions
+           JOIN blocks ON blocks.block_id = transactions.block_id
+          WHERE transactions.transaction_id = unspent_tx_outs.tx_out_id
+       ), 0);
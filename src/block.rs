@@ -1,9 +1,17 @@
-use std::time::{SystemTime, UNIX_EPOCH};
-use sha256::digest;
-use serde::Serialize;
+use std::{
+    sync::{
+        Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use sha2::{Digest, Sha256};
+use serde::{Deserialize, Serialize};
 use to_binary::BinaryString;
 use uuid7;
 use crate::{
+    block_chain::ChainConfig,
     database::sqlite::{
         SQLiteBlock,
         SQLiteTransaction,
@@ -14,10 +22,42 @@ use crate::{
         Transaction,
         tx_in::TxIn,
         tx_out::TxOut,
+        utxo_set::UtxoSet,
     },
 };
 
-#[derive(Clone, Serialize)]
+// How many hashes the proof-of-work search tries between progress reports
+const PROGRESS_INTERVAL: u64 = 1024;
+
+// The next block as handed to an external miner, which searches for a nonce (and may roll
+// the timestamp) whose hash meets the difficulty. The hash is the hex SHA-256 of index,
+// previous_hash, timestamp, serialized_data, difficulty and nonce written out one after
+// another, with the numbers in decimal.
+#[derive(Serialize)]
+pub struct BlockTemplate {
+    pub block_chain_id: String,
+    pub index: u32,
+    pub previous_hash: String,
+    pub difficulty: u32,
+    pub timestamp: u32,
+    pub data: Vec<Transaction>,
+    pub serialized_data: String,
+}
+
+// A solved template, as sent back by an external miner. Other template fields are ignored,
+// so a miner may send the template back with its timestamp and nonce filled in.
+#[derive(Deserialize)]
+pub struct BlockSolution {
+    pub block_chain_id: String,
+    pub index: u32,
+    pub previous_hash: String,
+    pub difficulty: u32,
+    pub timestamp: u32,
+    pub data: Vec<Transaction>,
+    pub nonce: u32,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Block {
     block_id: String,
     block_chain_id: String,
@@ -41,6 +81,7 @@ impl Block {
     pub fn difficulty(&self) -> u32 { self.difficulty }
     pub fn nonce(&self) -> u32 { self.nonce }
 
+    #[allow(clippy::too_many_arguments)]
     fn new(
         block_id: String,
         block_chain_id: String,
@@ -52,7 +93,7 @@ impl Block {
         difficulty: u32,
         nonce: u32,
     ) -> Self {
-        let hash = hash.unwrap_or(
+        let hash = hash.unwrap_or_else(|| {
             Self::calculate_hash(
                 index,
                 previous_hash.clone(),
@@ -61,7 +102,7 @@ impl Block {
                 difficulty,
                 nonce,
             )
-        );
+        });
         Block {
             block_id,
             block_chain_id,
@@ -77,18 +118,20 @@ impl Block {
 
     pub fn from_sqlite_block(
         sqlite_block: SQLiteBlock,
-        sqlite_transactions: Vec<SQLiteTransaction>,
-        sqlite_tx_ins: Vec<SQLiteTxIn>,
-        sqlite_tx_outs: Vec<SQLiteTxOut>,
+        sqlite_transactions: &[SQLiteTransaction],
+        sqlite_tx_ins: &[SQLiteTxIn],
+        sqlite_tx_outs: &[SQLiteTxOut],
     ) -> Self {
+        let block_id = sqlite_block.block_id.unwrap();
         Block {
-            block_id: sqlite_block.block_id.unwrap(),
+            block_id: block_id.clone(),
             block_chain_id: sqlite_block.block_chain_id,
             index: sqlite_block.block_index as u32,
             hash: sqlite_block.hash,
             previous_hash: sqlite_block.previous_hash,
             timestamp: sqlite_block.generate_timestamp as u32,
             data: sqlite_transactions.iter()
+                .filter(|transaction| transaction.block_id == block_id)
                 .map(|transaction| {
                     Transaction::new(
                         transaction.transaction_id.clone(),
@@ -177,39 +220,129 @@ impl Block {
     //     }
     // }
 
-    pub fn find_block(&self, data: Vec<Transaction>, difficulty: u32) -> Self {
-        let mut nonce: u32 = 0;
-        loop {
-            let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
-            let hash = Self::calculate_hash(
+    // Searches for the next block on `threads` threads, each trying its own share of the
+    // nonce space. A thread that runs out of nonces rolls the timestamp forward and starts
+    // over. Every PROGRESS_INTERVAL hashes, `should_stop` is told how many were tried and
+    // may end the search, in which case None is returned.
+    pub fn find_block(
+        &self,
+        data: Vec<Transaction>,
+        difficulty: u32,
+        threads: usize,
+        should_stop: impl Fn(u64) -> bool + Sync,
+    ) -> Option<Self> {
+        let index = self.index + 1;
+        // Only the timestamp and the nonce change between attempts
+        let serialized_data = Self::serialize_data(&data);
+        let nonce_count = u32::MAX as u64 + 1;
+        let threads = threads.clamp(1, u32::MAX as usize) as u64;
+        let is_done = AtomicBool::new(false);
+        let found: Mutex<Option<(u32, u32, String)>> = Mutex::new(None);
+
+        thread::scope(|scope| {
+            for thread_index in 0..threads {
+                let nonces = (nonce_count * thread_index / threads)..(nonce_count * (thread_index + 1) / threads);
+                let (serialized_data, is_done, found, should_stop) = (&serialized_data, &is_done, &found, &should_stop);
+                scope.spawn(move || {
+                    let mut timestamp = Self::now();
+                    let mut tried = 0;
+                    loop {
+                        // The prefix is hashed once per timestamp, and each nonce only hashes
+                        // its own digits on top of a copy of that state
+                        let prefix = Sha256::new_with_prefix(
+                            Self::hash_prefix(index, &self.hash, timestamp, serialized_data, difficulty),
+                        );
+                        for nonce in nonces.clone() {
+                            if tried == PROGRESS_INTERVAL {
+                                if is_done.load(Ordering::Relaxed) || should_stop(tried) {
+                                    is_done.store(true, Ordering::Relaxed);
+                                    return
+                                }
+                                tried = 0;
+                            }
+                            tried += 1;
+
+                            let hash = hex::encode(prefix.clone().chain_update(nonce.to_string()).finalize());
+                            if Self::is_matches_difficulty_hash(&hash, difficulty) {
+                                found.lock().unwrap().get_or_insert((timestamp, nonce as u32, hash));
+                                is_done.store(true, Ordering::Relaxed);
+                                should_stop(tried);
+                                return
+                            }
+                        }
+                        timestamp = Self::now().max(timestamp + 1);
+                    }
+                });
+            }
+        });
+
+        let (timestamp, nonce, hash) = found.into_inner().unwrap()?;
+        Some(Block::new(
+            uuid7::uuid7().to_string(),
+            self.block_chain_id(),
+            index,
+            Some(hash),
+            self.hash.clone(),
+            timestamp,
+            data,
+            difficulty,
+            nonce,
+        ))
+    }
+
+    // Mines the next block with a fixed timestamp, so that tests control how the difficulty
+    // adjusts
+    #[cfg(test)]
+    pub fn find_block_at(&self, data: Vec<Transaction>, difficulty: u32, timestamp: u32) -> Self {
+        (0..=u32::MAX)
+            .map(|nonce| Block::new(
+                uuid7::uuid7().to_string(),
+                self.block_chain_id(),
                 self.index + 1,
-                self.hash.clone(),
-                timestamp as u32,
+                None,
+                self.hash(),
+                timestamp,
                 data.clone(),
                 difficulty,
                 nonce,
-            );
-
-            if Self::is_matches_difficulty_hash(hash.clone(), difficulty) {
-                return Block::new(
-                    uuid7::uuid7().to_string(),
-                    self.block_chain_id(),
-                    self.index + 1,
-                    Some(hash),
-                    self.hash.clone(),
-                    timestamp as u32,
-                    data,
-                    difficulty,
-                    nonce,
-                )
-            }
+            ))
+            .find(|block| Self::is_matches_difficulty_hash(&block.hash, difficulty))
+            .unwrap()
+    }
 
-            nonce += 1;
+    pub fn template(&self, data: Vec<Transaction>, difficulty: u32) -> BlockTemplate {
+        BlockTemplate {
+            block_chain_id: self.block_chain_id(),
+            index: self.index + 1,
+            previous_hash: self.hash(),
+            difficulty,
+            timestamp: Self::now(),
+            serialized_data: Self::serialize_data(&data),
+            data,
         }
     }
 
-    fn is_matches_difficulty_hash(hash: String, difficulty: u32) -> bool {
-        let hex = BinaryString::from_hex(hash.as_str()).unwrap();
+    // The block a miner solved, with its hash worked out here
+    pub fn from_solution(solution: BlockSolution) -> Self {
+        Block::new(
+            uuid7::uuid7().to_string(),
+            solution.block_chain_id,
+            solution.index,
+            None,
+            solution.previous_hash,
+            solution.timestamp,
+            solution.data,
+            solution.difficulty,
+            solution.nonce,
+        )
+    }
+
+    fn now() -> u32 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as u32
+    }
+
+    fn is_matches_difficulty_hash(hash: &str, difficulty: u32) -> bool {
+        let hex = BinaryString::from_hex(hash).unwrap();
         for (i, char) in hex.0.chars().enumerate() {
             if i + 1 > difficulty as usize {
                 break;
@@ -218,7 +351,7 @@ impl Block {
                 return false
             }
         }
-        return true
+        true
     }
 
     pub fn is_valid_next_block(&self, next_block: Block) -> Result<(), &'static str> {
@@ -231,12 +364,63 @@ impl Block {
         if next_block.calculate_hash_for_block() != next_block.hash() {
             return Err("Invalid hash")
         }
-        if self.timestamp() - 60 > next_block.timestamp() || next_block.timestamp() - 60 > SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as u32 {
+        if !Self::is_matches_difficulty_hash(&next_block.hash(), next_block.difficulty()) {
+            return Err("Hash does not match the difficulty")
+        }
+        if self.timestamp().saturating_sub(60) > next_block.timestamp() || next_block.timestamp().saturating_sub(60) > Self::now() {
             return Err("Invalid timestamp")
         }
         Ok(())
     }
 
+    pub fn is_valid_transactions(&self, utxo_set: &UtxoSet, config: &ChainConfig) -> Result<(), String> {
+        Self::validate_transactions(self.index, &self.data, utxo_set, config)
+    }
+
+    // Validates the transactions of a block at `block_index` against the UTXO set of its parent
+    pub fn validate_transactions(
+        block_index: u32,
+        transactions: &[Transaction],
+        utxo_set: &UtxoSet,
+        config: &ChainConfig,
+    ) -> Result<(), String> {
+        let coinbase_transaction = match transactions.first() {
+            Some(v) => v,
+            None => return Err("Block must contain a coinbase transaction".to_string()),
+        };
+
+        for transaction in transactions[1..].iter() {
+            transaction.is_valid()?;
+            for tx_in in transaction.tx_in_list().iter() {
+                tx_in.is_valid(transaction, utxo_set)?;
+            }
+            transaction.check_amount_between_in_and_out(utxo_set)?;
+            transaction.check_maturity(utxo_set, block_index, config.coinbase_maturity)?;
+        }
+
+        coinbase_transaction.is_valid()?;
+        coinbase_transaction.is_coinbase(
+            block_index as usize,
+            Transaction::total_fee(&transactions[1..], utxo_set),
+            &config.subsidy_schedule,
+        )?;
+
+        let tx_in_list = transactions[1..]
+            .iter()
+            .flat_map(|transaction| transaction.tx_in_list())
+            .collect::<Vec<TxIn>>();
+        for (index, tx_in) in tx_in_list.iter().enumerate() {
+            if tx_in_list[..index].iter().any(|other| {
+                other.tx_out_id() == tx_in.tx_out_id()
+                && other.tx_out_index() == tx_in.tx_out_index()
+            }) {
+                return Err("Block contains a double spend".to_string())
+            }
+        }
+
+        Ok(())
+    }
+
     fn calculate_hash_for_block(&self) -> String {
         Self::calculate_hash(
             self.index,
@@ -256,16 +440,204 @@ impl Block {
         difficulty: u32,
         nonce: u32,
     ) -> String {
-        digest(format!("{}{}{}{}{}{}",
-            index,
-            previous_hash,
-            timestamp,
-            data.iter()
-                .map(|v| format!("{}", v))
-                .reduce(|a, b| format!("{}{}", a, b))
-                .unwrap(),
-            difficulty,
+        let prefix = Self::hash_prefix(index, &previous_hash, timestamp, &Self::serialize_data(&data), difficulty);
+        hex::encode(Sha256::digest(format!("{}{}", prefix, nonce)))
+    }
+
+    // Everything that is hashed ahead of the nonce
+    fn hash_prefix(
+        index: u32,
+        previous_hash: &str,
+        timestamp: u32,
+        serialized_data: &str,
+        difficulty: u32,
+    ) -> String {
+        format!("{}{}{}{}{}", index, previous_hash, timestamp, serialized_data, difficulty)
+    }
+
+    fn serialize_data(data: &[Transaction]) -> String {
+        data.iter()
+            .map(|v| format!("{}", v))
+            .collect::<String>()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        mylib::ecdsa::{generate_private_key, public_key_from_private_key, sign},
+        transaction::{
+            subsidy::SubsidySchedule,
+            utxo::UTxO,
+        },
+    };
+
+    fn coinbase(address: String, block_index: u32, total_fee: u32) -> Transaction {
+        Transaction::coinbase(address, block_index, total_fee, &SubsidySchedule::default())
+    }
+
+    // A spendable output of 100 held by a fresh key, along with that key
+    fn funded_key() -> (String, String, UTxO) {
+        let private_key = generate_private_key();
+        let address = public_key_from_private_key(private_key.clone()).unwrap();
+        let utxo = UTxO::new("a".repeat(64), 0, address.clone(), 100, false, 0);
+        (private_key, address, utxo)
+    }
+
+    // Spends `utxo` into `tx_out_list`, signed with `signing_key` whether or not it owns `utxo`
+    fn spend(utxo: &UTxO, signing_key: String, tx_out_list: Vec<TxOut>) -> Transaction {
+        let tx_in_list = vec![TxIn::new(utxo.tx_out_id(), utxo.tx_out_index(), "".to_string())];
+        let id = Transaction::calculate_transaction_id(tx_in_list, tx_out_list.clone());
+        let signature = sign(signing_key, id.clone()).unwrap();
+        Transaction::new(
+            Some(id),
+            vec![TxIn::new(utxo.tx_out_id(), utxo.tx_out_index(), signature)],
+            tx_out_list,
+        )
+    }
+
+    #[test]
+    fn found_block_hash_matches_its_contents() {
+        let genesis_block = Block::get_genesis();
+        for threads in [1, 4] {
+            let block = genesis_block.find_block(vec![], genesis_block.difficulty(), threads, |_| false).unwrap();
+            assert_eq!(block.hash(), block.calculate_hash_for_block());
+            assert_eq!(genesis_block.is_valid_next_block(block), Ok(()));
+        }
+    }
+
+    #[test]
+    fn template_can_be_solved_from_its_fields() {
+        let genesis_block = Block::get_genesis();
+        let address = public_key_from_private_key(generate_private_key()).unwrap();
+        let template = genesis_block.template(vec![coinbase(address, 1, 0)], genesis_block.difficulty());
+
+        // As an external miner would, from the documented hash input
+        let nonce = (0..=u32::MAX)
+            .find(|nonce| {
+                let hash = hex::encode(Sha256::digest(format!(
+                    "{}{}{}{}{}{}",
+                    template.index, template.previous_hash, template.timestamp,
+                    template.serialized_data, template.difficulty, nonce,
+                )));
+                Block::is_matches_difficulty_hash(&hash, template.difficulty)
+            })
+            .unwrap();
+        let block = Block::from_solution(BlockSolution {
+            block_chain_id: template.block_chain_id,
+            index: template.index,
+            previous_hash: template.previous_hash,
+            difficulty: template.difficulty,
+            timestamp: template.timestamp,
+            data: template.data,
             nonce,
-        ).to_string())
+        });
+        assert_eq!(genesis_block.is_valid_next_block(block), Ok(()));
+    }
+
+    #[test]
+    fn coinbase_outputs_are_spendable_once_mature() {
+        let private_key = generate_private_key();
+        let address = public_key_from_private_key(private_key.clone()).unwrap();
+        let mined_at = 5;
+        let utxo_set = UtxoSet::new(coinbase(address.clone(), mined_at, 0).new_utxo(true, mined_at));
+        let spend = Transaction::signed(&utxo_set.utxo_list(), private_key, address.clone());
+        let block_data = |block_index| vec![coinbase(address.clone(), block_index, 0), spend.clone()];
+
+        for config in [ChainConfig::default(), ChainConfig { coinbase_maturity: 3, ..ChainConfig::default() }] {
+            let block_index = mined_at + config.coinbase_maturity - 1;
+            assert_eq!(
+                Block::validate_transactions(block_index, &block_data(block_index), &utxo_set, &config),
+                Err("TxIn spends an immature coinbase output".to_string()),
+            );
+            let block_index = mined_at + config.coinbase_maturity;
+            assert_eq!(Block::validate_transactions(block_index, &block_data(block_index), &utxo_set, &config), Ok(()));
+        }
+    }
+
+    #[test]
+    fn accepts_a_coinbase_claiming_the_fees() {
+        let (private_key, address, utxo) = funded_key();
+        let utxo_set = UtxoSet::new(vec![utxo.clone()]);
+        let transaction = spend(&utxo, private_key, vec![TxOut::new(address.clone(), 90)]);
+        let transactions = vec![coinbase(address, 1, 10), transaction];
+        assert_eq!(Block::validate_transactions(1, &transactions, &utxo_set, &ChainConfig::default()), Ok(()));
+    }
+
+    #[test]
+    fn rejects_a_block_without_coinbase() {
+        assert_eq!(
+            Block::validate_transactions(1, &[], &UtxoSet::default(), &ChainConfig::default()),
+            Err("Block must contain a coinbase transaction".to_string()),
+        );
+    }
+
+    #[test]
+    fn rejects_a_coinbase_that_is_not_first() {
+        let (private_key, address, utxo) = funded_key();
+        let utxo_set = UtxoSet::new(vec![utxo.clone()]);
+        let transaction = spend(&utxo, private_key, vec![TxOut::new(address.clone(), 100)]);
+
+        // The coinbase's TxIn refers to no output, so it fails as an ordinary transaction
+        let transactions = vec![transaction.clone(), coinbase(address.clone(), 1, 0)];
+        assert_eq!(
+            Block::validate_transactions(1, &transactions, &utxo_set, &ChainConfig::default()),
+            Err("Failed to find referenced UTxO".to_string()),
+        );
+        // And a block whose first transaction is an ordinary one has no coinbase
+        assert_eq!(
+            Block::validate_transactions(1, &[transaction], &utxo_set, &ChainConfig::default()),
+            Err("The TxIn index in coinbase tx must be the block height".to_string()),
+        );
+    }
+
+    #[test]
+    fn rejects_a_wrong_coinbase_amount() {
+        let (private_key, address, utxo) = funded_key();
+        let utxo_set = UtxoSet::new(vec![utxo.clone()]);
+        let transaction = spend(&utxo, private_key, vec![TxOut::new(address.clone(), 90)]);
+        for total_fee in [9, 11] {
+            let transactions = vec![coinbase(address.clone(), 1, total_fee), transaction.clone()];
+            assert_eq!(
+                Block::validate_transactions(1, &transactions, &utxo_set, &ChainConfig::default()),
+                Err("Invalid coinbase amount in coinbase transaction".to_string()),
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_a_bad_signature() {
+        let (_, address, utxo) = funded_key();
+        let utxo_set = UtxoSet::new(vec![utxo.clone()]);
+        let transaction = spend(&utxo, generate_private_key(), vec![TxOut::new(address.clone(), 100)]);
+        let transactions = vec![coinbase(address, 1, 0), transaction];
+        assert!(Block::validate_transactions(1, &transactions, &utxo_set, &ChainConfig::default()).is_err());
+    }
+
+    #[test]
+    fn rejects_outputs_worth_more_than_inputs() {
+        let (private_key, address, utxo) = funded_key();
+        let utxo_set = UtxoSet::new(vec![utxo.clone()]);
+        let transaction = spend(&utxo, private_key, vec![TxOut::new(address.clone(), 101)]);
+        let transactions = vec![coinbase(address, 1, 0), transaction];
+        assert_eq!(
+            Block::validate_transactions(1, &transactions, &utxo_set, &ChainConfig::default()),
+            Err("Total value of TxOut exceeds that of TxIn".to_string()),
+        );
+    }
+
+    #[test]
+    fn rejects_a_double_spend_within_the_block() {
+        let (private_key, address, utxo) = funded_key();
+        let utxo_set = UtxoSet::new(vec![utxo.clone()]);
+        let (_, other_address, _) = funded_key();
+        let first = spend(&utxo, private_key.clone(), vec![TxOut::new(address.clone(), 100)]);
+        let second = spend(&utxo, private_key, vec![TxOut::new(other_address, 100)]);
+        let transactions = vec![coinbase(address, 1, 0), first, second];
+        assert_eq!(
+            Block::validate_transactions(1, &transactions, &utxo_set, &ChainConfig::default()),
+            Err("Block contains a double spend".to_string()),
+        );
     }
 }
@@ -1,102 +1,335 @@
 use serde::Serialize;
 use crate::{
     block::Block,
-    transaction::Transaction,
+    transaction::{
+        subsidy::SubsidySchedule,
+        utxo::{COINBASE_MATURITY, UTxO},
+        utxo_set::UtxoSet,
+    },
     database::sqlite::{
         SQLiteBlock,
         SQLiteTransaction,
         SQLiteTxIn,
         SQLiteTxOut,
+        SQLiteUnspentTxOut,
     },
 };
 
 pub const BLOCK_GENERATION_INTERVAL: u32 = 10;
 pub const DIFFICULTY_ADJUSTMENT_INTERVAL: u32 = 10;
 
+// Consensus rules that every node of a network has to agree on, set when the node starts
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChainConfig {
+    pub subsidy_schedule: SubsidySchedule,
+    pub coinbase_maturity: u32,
+}
+
+impl Default for ChainConfig {
+    fn default() -> Self {
+        ChainConfig {
+            subsidy_schedule: SubsidySchedule::default(),
+            coinbase_maturity: COINBASE_MATURITY,
+        }
+    }
+}
+
 #[derive(Clone, Serialize)]
-pub struct BlockChain(Vec<Block>);
+#[serde(transparent)]
+pub struct BlockChain {
+    blocks: Vec<Block>,
+    #[serde(skip)]
+    utxo_set: UtxoSet,
+    #[serde(skip)]
+    config: ChainConfig,
+}
 
 impl BlockChain {
-    pub fn generate() -> Self {
-        BlockChain(vec![Block::get_genesis()])
+    pub fn blocks(&self) -> &[Block] { &self.blocks }
+    pub fn utxo_set(&self) -> &UtxoSet { &self.utxo_set }
+    pub fn config(&self) -> &ChainConfig { &self.config }
+
+    pub fn new(blocks: Vec<Block>, config: ChainConfig) -> Self {
+        let utxo_set = UtxoSet::from_blocks(&blocks);
+        BlockChain { blocks, utxo_set, config }
+    }
+
+    pub fn generate(config: ChainConfig) -> Self {
+        BlockChain::new(vec![Block::get_genesis()], config)
     }
 
     pub fn from_sqlite_blocks(
+        config: ChainConfig,
         sqlite_blocks: Vec<SQLiteBlock>,
         sqlite_transactions: Vec<SQLiteTransaction>,
         sqlite_tx_ins: Vec<SQLiteTxIn>,
         sqlite_tx_outs: Vec<SQLiteTxOut>,
-    ) -> Result<Self, &'static str> {
-        let mut block_chain = BlockChain(vec![]);
-        block_chain.0 = sqlite_blocks
+        sqlite_unspent_tx_outs: Vec<SQLiteUnspentTxOut>,
+    ) -> Result<Self, String> {
+        // The genesis block is hardcoded and never persisted, so stored blocks start at index 1
+        let mut block_chain = BlockChain::generate(config);
+        block_chain.blocks.extend(sqlite_blocks
             .iter()
             .map(|b| {
                 Block::from_sqlite_block(
                     b.clone(),
-                    sqlite_transactions.clone(),
-                    sqlite_tx_ins.clone(),
-                    sqlite_tx_outs.clone(),
+                    &sqlite_transactions,
+                    &sqlite_tx_ins,
+                    &sqlite_tx_outs,
                 )
             })
-            .collect();
+        );
 
-        match block_chain.is_valid_chain() {
-            Ok(()) => Ok(block_chain),
-            Err(message) => Err(message),
-        }
+        // The persisted UTXO set is trusted, so restarts do not replay the chain. Databases
+        // written before the set was persisted have none yet, and are replayed and validated.
+        block_chain.utxo_set = if sqlite_unspent_tx_outs.is_empty() {
+            Self::validate_blocks(&block_chain.blocks, &block_chain.config)?
+        } else {
+            UtxoSet::new(
+                sqlite_unspent_tx_outs
+                    .iter()
+                    .map(|utxo| {
+                        UTxO::new(
+                            utxo.tx_out_id.clone(),
+                            utxo.tx_out_index as usize,
+                            utxo.address.clone(),
+                            utxo.amount as u32,
+                            utxo.is_coinbase,
+                            utxo.block_index as u32,
+                        )
+                    })
+                    .collect()
+            )
+        };
+
+        Ok(block_chain)
     }
 
     pub fn latest_block(&self) -> Block {
-        self.0[self.0.len() - 1].clone()
+        self.blocks[self.blocks.len() - 1].clone()
     }
 
-    pub fn is_valid_chain(&self) -> Result<(), &'static str> {
-        if self.0[0].hash() != Block::get_genesis().hash() {
-            return Err("Invalid genesis block")
+    pub fn is_valid_chain(&self) -> Result<(), String> {
+        Self::validate_blocks(&self.blocks, &self.config)?;
+        Ok(())
+    }
+
+    // Validates every block against its parent and returns the UTXO set at the tip
+    fn validate_blocks(blocks: &[Block], config: &ChainConfig) -> Result<UtxoSet, String> {
+        if blocks[0].hash() != Block::get_genesis().hash() {
+            return Err("Invalid genesis block".to_string())
         }
-        for index in 1..self.0.len() {
-            match self.0[index - 1].is_valid_next_block(self.0[index].clone()) {
-                Ok(()) => {},
-                Err(message) => {
-                    return Err(message)
-                }
-            };
+
+        let mut utxo_set = UtxoSet::from_blocks(&blocks[..1]);
+        for index in 1..blocks.len() {
+            // The difficulty schedule is checked first, so that the proof of work is
+            // measured against the difficulty the chain requires
+            if blocks[index].difficulty() != Self::difficulty_for_next_block(&blocks[..index]) {
+                return Err("Invalid difficulty".to_string())
+            }
+            blocks[index - 1].is_valid_next_block(blocks[index].clone())?;
+            blocks[index].is_valid_transactions(&utxo_set, config)?;
+            utxo_set.apply_transactions(blocks[index].index(), &blocks[index].data());
         }
+        Ok(utxo_set)
+    }
+
+    pub fn add_block(&mut self, block: Block) -> Result<(), String> {
+        self.validate_block(&block)?;
+        self.append_block(block);
         Ok(())
     }
 
-    pub fn append_new_block(&mut self, data: Vec<Transaction>) -> Block {
-        let new_block = self.latest_block().find_block(data, self.adjusted_difficulty());
-        self.0.push(new_block.clone());
+    // Checks that `block` may follow the tip, without adding it
+    pub fn validate_block(&self, block: &Block) -> Result<(), String> {
+        if block.difficulty() != self.adjusted_difficulty() {
+            return Err("Invalid difficulty".to_string())
+        }
+        self.latest_block().is_valid_next_block(block.clone())?;
+        block.is_valid_transactions(&self.utxo_set, &self.config)?;
+        Ok(())
+    }
 
-        new_block
+    // Adds a block that has passed validate_block, so that callers can persist it first
+    pub fn append_block(&mut self, block: Block) {
+        self.utxo_set.apply_transactions(block.index(), &block.data());
+        self.blocks.push(block);
     }
 
-    fn adjusted_difficulty(&self) -> u32 {
-        let latest_block = self.latest_block();
-        if latest_block.index() % DIFFICULTY_ADJUSTMENT_INTERVAL != 0 || latest_block.index() == 0 {
+    pub fn adjusted_difficulty(&self) -> u32 {
+        Self::difficulty_for_next_block(&self.blocks)
+    }
+
+    // Difficulty required for the block following the last one in `blocks`
+    fn difficulty_for_next_block(blocks: &[Block]) -> u32 {
+        let latest_block = blocks[blocks.len() - 1].clone();
+        if !latest_block.index().is_multiple_of(DIFFICULTY_ADJUSTMENT_INTERVAL) || latest_block.index() == 0 {
             return latest_block.difficulty()
         }
 
-        let previous_adjustment_block = self.0[self.0.len() - DIFFICULTY_ADJUSTMENT_INTERVAL as usize].clone();
+        let previous_adjustment_block = blocks[blocks.len() - DIFFICULTY_ADJUSTMENT_INTERVAL as usize].clone();
         let time_expected = BLOCK_GENERATION_INTERVAL * DIFFICULTY_ADJUSTMENT_INTERVAL;
-        let time_taken = latest_block.timestamp() - previous_adjustment_block.timestamp();
+        let time_taken = latest_block.timestamp().saturating_sub(previous_adjustment_block.timestamp());
 
         if time_taken < time_expected / 2 {
-            return previous_adjustment_block.difficulty() + 1
+            previous_adjustment_block.difficulty() + 1
         } else if time_taken > time_expected * 2 {
-            return previous_adjustment_block.difficulty() - 1
+            previous_adjustment_block.difficulty().saturating_sub(1)
         } else {
-            return previous_adjustment_block.difficulty()
+            previous_adjustment_block.difficulty()
         }
     }
 
+    // Accumulated work of the chain, counting 2^difficulty per block
+    pub fn cumulative_difficulty(&self) -> u128 {
+        self.blocks.iter()
+            .map(|block| 1_u128.checked_shl(block.difficulty()).unwrap_or(u128::MAX))
+            .fold(0, |a, b| a.saturating_add(b))
+    }
+
+    // The chain with more accumulated work wins. On a tie the chain whose tip hash is
+    // lexicographically smaller wins, so that every node picks the same chain.
+    fn is_preferred_over(&self, other: &BlockChain) -> bool {
+        let difficulty = self.cumulative_difficulty();
+        let other_difficulty = other.cumulative_difficulty();
+        if difficulty != other_difficulty {
+            return difficulty > other_difficulty
+        }
+        self.latest_block().hash() < other.latest_block().hash()
+    }
+
     pub fn replace_chain(&mut self, new_chain: BlockChain) -> Result<(), String>{
-        if new_chain.is_valid_chain().is_err() || new_chain.0.len() <= self.0.len() {
-            return Err("Invalid chain received".to_string())
+        *self = self.validate_replacement(new_chain)?;
+        Ok(())
+    }
+
+    // Returns `new_chain` with its UTXO set replayed if it is valid and should replace this
+    // chain, so that callers can persist it before swapping it in
+    pub fn validate_replacement(&self, new_chain: BlockChain) -> Result<BlockChain, String> {
+        let utxo_set = match Self::validate_blocks(&new_chain.blocks, &self.config) {
+            Ok(v) => v,
+            Err(_) => return Err("Invalid chain received".to_string()),
+        };
+        if !new_chain.is_preferred_over(self) {
+            return Err("Received chain does not have more cumulative difficulty".to_string())
         }
 
-        self.0 = new_chain.0;
-        return Ok(())
+        Ok(BlockChain { blocks: new_chain.blocks, utxo_set, config: self.config.clone() })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::Transaction;
+
+    const ADDRESS: &str = "miner";
+    const NOT_PREFERRED: &str = "Received chain does not have more cumulative difficulty";
+
+    // A chain of `len` blocks after the genesis block, each mined `interval` seconds after
+    // its parent. Blocks 11 and later are mined at a higher difficulty when the interval is
+    // short, and at a lower one when it is long.
+    fn chain(len: u32, interval: u32) -> BlockChain {
+        chain_with(ChainConfig::default(), len, interval)
+    }
+
+    fn chain_with(config: ChainConfig, len: u32, interval: u32) -> BlockChain {
+        let mut block_chain = BlockChain::generate(config);
+        for _ in 0..len {
+            let latest_block = block_chain.latest_block();
+            let coinbase = Transaction::coinbase(
+                ADDRESS.to_string(),
+                latest_block.index() + 1,
+                0,
+                &block_chain.config().subsidy_schedule,
+            );
+            let block = latest_block.find_block_at(
+                vec![coinbase],
+                block_chain.adjusted_difficulty(),
+                latest_block.timestamp() + interval,
+            );
+            block_chain.add_block(block).unwrap();
+        }
+        block_chain
+    }
+
+    #[test]
+    fn rejects_early_timestamps_without_overflowing() {
+        let genesis_block = Block::get_genesis();
+        let block = genesis_block.find_block_at(vec![], genesis_block.difficulty(), 30);
+        assert_eq!(genesis_block.is_valid_next_block(block), Err("Invalid timestamp"));
+    }
+
+    #[test]
+    fn checks_the_difficulty_schedule_before_the_block() {
+        let mut block_chain = BlockChain::generate(ChainConfig::default());
+        let block = block_chain.latest_block().find_block_at(vec![], 0, 30);
+        assert_eq!(block_chain.add_block(block.clone()), Err("Invalid difficulty".to_string()));
+        assert_eq!(
+            BlockChain::validate_blocks(&[Block::get_genesis(), block], &ChainConfig::default()).err(),
+            Some("Invalid difficulty".to_string()),
+        );
+    }
+
+    #[test]
+    fn more_work_beats_a_longer_chain() {
+        let heavier = chain(12, 1);
+        let longer = chain(15, 30);
+        assert!(heavier.blocks.len() < longer.blocks.len());
+        assert!(heavier.cumulative_difficulty() > longer.cumulative_difficulty());
+
+        assert!(heavier.is_preferred_over(&longer));
+        assert!(!longer.is_preferred_over(&heavier));
+
+        let mut block_chain = longer;
+        assert_eq!(block_chain.replace_chain(heavier.clone()), Ok(()));
+        assert_eq!(block_chain.latest_block().hash(), heavier.latest_block().hash());
+    }
+
+    #[test]
+    fn equal_work_goes_to_the_lower_tip_hash() {
+        let (a, b) = (chain(1, 10), chain(1, 20));
+        assert_eq!(a.cumulative_difficulty(), b.cumulative_difficulty());
+        let (lower, higher) = if a.latest_block().hash() < b.latest_block().hash() { (a, b) } else { (b, a) };
+
+        assert!(lower.is_preferred_over(&higher));
+        assert!(!higher.is_preferred_over(&lower));
+
+        let mut block_chain = lower.clone();
+        assert_eq!(block_chain.replace_chain(higher.clone()), Err(NOT_PREFERRED.to_string()));
+        assert_eq!(block_chain.latest_block().hash(), lower.latest_block().hash());
+
+        let mut block_chain = higher;
+        assert_eq!(block_chain.replace_chain(lower.clone()), Ok(()));
+        assert_eq!(block_chain.latest_block().hash(), lower.latest_block().hash());
+    }
+
+    #[test]
+    fn replace_chain_rejects_a_chain_with_only_more_blocks() {
+        let mut block_chain = chain(12, 1);
+        let tip = block_chain.latest_block().hash();
+
+        assert_eq!(block_chain.replace_chain(chain(15, 30)), Err(NOT_PREFERRED.to_string()));
+        assert_eq!(block_chain.latest_block().hash(), tip);
+    }
+
+    #[test]
+    fn validates_blocks_against_its_own_subsidy_schedule() {
+        let config = ChainConfig {
+            subsidy_schedule: SubsidySchedule { initial_subsidy: 10, ..SubsidySchedule::default() },
+            ..ChainConfig::default()
+        };
+        let other = chain_with(config.clone(), 2, 30);
+        assert_eq!(other.is_valid_chain(), Ok(()));
+
+        let mut block_chain = BlockChain::generate(ChainConfig::default());
+        assert_eq!(
+            block_chain.add_block(other.blocks[1].clone()),
+            Err("Invalid coinbase amount in coinbase transaction".to_string()),
+        );
+        assert_eq!(block_chain.replace_chain(other.clone()), Err("Invalid chain received".to_string()));
+
+        let mut block_chain = BlockChain::generate(config);
+        assert_eq!(block_chain.replace_chain(other), Ok(()));
     }
 }
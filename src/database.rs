@@ -1,10 +1,11 @@
 pub mod sqlite;
-use crate::block_chain::BlockChain;
+use std::future::Future;
+use crate::block_chain::{BlockChain, ChainConfig};
 use crate::block::Block;
 use sqlx;
 
-#[allow(async_fn_in_trait)]
 pub trait Database {
-    async fn find_block_chain(&self) -> Result<BlockChain, sqlx::Error>;
-    async fn save_block(&self, block: Block) -> Result<(), sqlx::Error>;
+    fn find_block_chain(&self, config: ChainConfig) -> impl Future<Output = Result<BlockChain, sqlx::Error>> + Send;
+    fn save_block(&self, block: Block) -> impl Future<Output = Result<(), sqlx::Error>> + Send;
+    fn replace_block_chain(&self, block_chain: &BlockChain) -> impl Future<Output = Result<(), sqlx::Error>> + Send;
 }
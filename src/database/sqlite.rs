@@ -1,6 +1,6 @@
-use sqlx::sqlite::SqlitePool;
+use sqlx::sqlite::{Sqlite, SqlitePool};
 use uuid7;
-use crate::block_chain::BlockChain;
+use crate::block_chain::{BlockChain, ChainConfig};
 use crate::block::Block;
 
 #[derive(Clone)]
@@ -14,80 +14,12 @@ impl SqliteDatabase {
             pool,
         }
     }
-}
-
-impl super::Database for SqliteDatabase {
-    async fn find_block_chain(&self) -> Result<BlockChain, sqlx::Error> {
-        let blocks = sqlx::query_as!(
-            SQLiteBlock,
-            r#"
-                SELECT
-                    block_id,
-                    block_chain_id,
-                    block_index,
-                    hash,
-                    previous_hash,
-                    generate_timestamp,
-                    difficulty,
-                    nonce
-                  FROM blocks
-            "#,
-        )
-        .fetch_all(&self.pool)
-        .await?;
-
-        let transactions = sqlx::query_as!(
-            SQLiteTransaction,
-            r#"
-                SELECT
-                    transaction_id,
-                    block_id
-                  FROM transactions
-            "#
-        )
-        .fetch_all(&self.pool)
-        .await?;
-
-        let tx_ins = sqlx::query_as!(
-            SQLiteTxIn,
-            r#"
-                SELECT
-                    tx_in_id,
-                    transaction_id,
-                    tx_out_id,
-                    tx_out_index,
-                    signature
-                  FROM tx_ins
-            "#
-        )
-        .fetch_all(&self.pool)
-        .await?;
 
-        let tx_outs = sqlx::query_as!(
-            SQLiteTxOut,
-            r#"
-                SELECT
-                    tx_out_id,
-                    transaction_id,
-                    address,
-                    amount
-                  FROM tx_outs
-            "#
-        )
-        .fetch_all(&self.pool)
-        .await?;
-
-        let block_chain = BlockChain::from_sqlite_blocks(
-            blocks,
-            transactions,
-            tx_ins,
-            tx_outs,
-        ).unwrap();
-
-        Ok(block_chain)
-    }
-
-    async fn save_block(&self, block: Block) -> Result<(), sqlx::Error> {
+    // Inserts the block with its transactions and applies its spent and created outputs to unspent_tx_outs
+    async fn insert_block(
+        db_transaction: &mut sqlx::Transaction<'_, Sqlite>,
+        block: &Block,
+    ) -> Result<(), sqlx::Error> {
         let block_id = block.block_id();
         let block_chain_id = block.block_chain_id();
         let index = block.index() as i64;
@@ -118,11 +50,12 @@ impl super::Database for SqliteDatabase {
             difficulty,
             nonce,
         )
-        .execute(&self.pool)
+        .execute(&mut **db_transaction)
         .await?;
 
-        for transaction in block.data().iter() {
+        for (transaction_index, transaction) in block.data().iter().enumerate() {
             let transaction_id = transaction.id();
+            let is_coinbase = transaction_index == 0;
             sqlx::query!(
                 r#"
                     INSERT INTO transactions(
@@ -133,7 +66,7 @@ impl super::Database for SqliteDatabase {
                 transaction_id,
                 block_id,
             )
-            .execute(&self.pool)
+            .execute(&mut **db_transaction)
             .await?;
 
             for tx_in in transaction.tx_in_list().iter() {
@@ -157,10 +90,22 @@ impl super::Database for SqliteDatabase {
                     tx_out_index,
                     signature,
                 )
-                .execute(&self.pool)
+                .execute(&mut **db_transaction)
+                .await?;
+
+                sqlx::query!(
+                    r#"
+                        DELETE FROM unspent_tx_outs
+                         WHERE tx_out_id = ?
+                           AND tx_out_index = ?
+                    "#,
+                    tx_out_id,
+                    tx_out_index,
+                )
+                .execute(&mut **db_transaction)
                 .await?;
             }
-            for tx_out in transaction.tx_out_list().iter() {
+            for (tx_out_index, tx_out) in transaction.tx_out_list().iter().enumerate() {
                 let tx_out_id = uuid7::uuid7().to_string(); // FIXME
                 let address = tx_out.address();
                 let amount = tx_out.amount();
@@ -178,11 +123,176 @@ impl super::Database for SqliteDatabase {
                     address,
                     amount,
                 )
-                .execute(&self.pool)
+                .execute(&mut **db_transaction)
+                .await?;
+
+                let tx_out_index = tx_out_index as i64;
+                sqlx::query!(
+                    r#"
+                        INSERT INTO unspent_tx_outs(
+                            tx_out_id,
+                            tx_out_index,
+                            address,
+                            amount,
+                            is_coinbase,
+                            block_index
+                        ) VALUES (?, ?, ?, ?, ?, ?)
+                    "#,
+                    transaction_id,
+                    tx_out_index,
+                    address,
+                    amount,
+                    is_coinbase,
+                    index,
+                )
+                .execute(&mut **db_transaction)
                 .await?;
             }
         }
 
+        Ok(())
+    }
+
+    // Rewrites unspent_tx_outs with the UTXO set of `block_chain`
+    async fn replace_unspent_tx_outs(&self, block_chain: &BlockChain) -> Result<(), sqlx::Error> {
+        let mut db_transaction = self.pool.begin().await?;
+        sqlx::query!("DELETE FROM unspent_tx_outs").execute(&mut *db_transaction).await?;
+
+        for utxo in block_chain.utxo_set().utxo_list().iter() {
+            let tx_out_id = utxo.tx_out_id();
+            let tx_out_index = utxo.tx_out_index() as i64;
+            let address = utxo.address();
+            let amount = utxo.amount();
+            let is_coinbase = utxo.is_coinbase();
+            let block_index = utxo.block_index() as i64;
+            sqlx::query!(
+                r#"
+                    INSERT INTO unspent_tx_outs(
+                        tx_out_id,
+                        tx_out_index,
+                        address,
+                        amount,
+                        is_coinbase,
+                        block_index
+                    ) VALUES (?, ?, ?, ?, ?, ?)
+                "#,
+                tx_out_id,
+                tx_out_index,
+                address,
+                amount,
+                is_coinbase,
+                block_index,
+            )
+            .execute(&mut *db_transaction)
+            .await?;
+        }
+
+        db_transaction.commit().await?;
+        Ok(())
+    }
+}
+
+impl super::Database for SqliteDatabase {
+    async fn find_block_chain(&self, config: ChainConfig) -> Result<BlockChain, sqlx::Error> {
+        let blocks = sqlx::query_as!(
+            SQLiteBlock,
+            r#"
+                SELECT
+                    block_id,
+                    block_chain_id,
+                    block_index,
+                    hash,
+                    previous_hash,
+                    generate_timestamp,
+                    difficulty,
+                    nonce
+                  FROM blocks
+                 ORDER BY block_index
+            "#,
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let transactions = sqlx::query_as!(
+            SQLiteTransaction,
+            r#"
+                SELECT
+                    transaction_id,
+                    block_id
+                  FROM transactions
+            "#
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let tx_ins = sqlx::query_as!(
+            SQLiteTxIn,
+            r#"
+                SELECT
+                    tx_in_id,
+                    transaction_id,
+                    tx_out_id,
+                    tx_out_index,
+                    signature
+                  FROM tx_ins
+            "#
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let tx_outs = sqlx::query_as!(
+            SQLiteTxOut,
+            r#"
+                SELECT
+                    tx_out_id,
+                    transaction_id,
+                    address,
+                    amount
+                  FROM tx_outs
+            "#
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let unspent_tx_outs = sqlx::query_as!(
+            SQLiteUnspentTxOut,
+            r#"
+                SELECT
+                    tx_out_id,
+                    tx_out_index,
+                    address,
+                    amount,
+                    is_coinbase,
+                    block_index
+                  FROM unspent_tx_outs
+            "#
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        // A chain stored before the UTXO set was persisted is replayed on load. The set is
+        // written back, so that blocks saved from now on keep a complete set up to date.
+        let is_utxo_set_missing = unspent_tx_outs.is_empty() && !blocks.is_empty();
+        let block_chain = BlockChain::from_sqlite_blocks(
+            config,
+            blocks,
+            transactions,
+            tx_ins,
+            tx_outs,
+            unspent_tx_outs,
+        ).unwrap();
+        if is_utxo_set_missing {
+            self.replace_unspent_tx_outs(&block_chain).await?;
+        }
+
+        Ok(block_chain)
+    }
+
+    async fn save_block(&self, block: Block) -> Result<(), sqlx::Error> {
+        let mut db_transaction = self.pool.begin().await?;
+        Self::insert_block(&mut db_transaction, &block).await?;
+        db_transaction.commit().await?;
+
         // let block = SQLiteBlock {
         //     block_id: block.block_id(),
         //     block_chain_id: block.block_chain_id(),
@@ -220,6 +330,23 @@ impl super::Database for SqliteDatabase {
 
         Ok(())
     }
+
+    async fn replace_block_chain(&self, block_chain: &BlockChain) -> Result<(), sqlx::Error> {
+        let mut db_transaction = self.pool.begin().await?;
+        sqlx::query!("DELETE FROM blocks").execute(&mut *db_transaction).await?;
+        sqlx::query!("DELETE FROM transactions").execute(&mut *db_transaction).await?;
+        sqlx::query!("DELETE FROM tx_ins").execute(&mut *db_transaction).await?;
+        sqlx::query!("DELETE FROM tx_outs").execute(&mut *db_transaction).await?;
+        sqlx::query!("DELETE FROM unspent_tx_outs").execute(&mut *db_transaction).await?;
+
+        // The genesis block is not persisted
+        for block in block_chain.blocks()[1..].iter() {
+            Self::insert_block(&mut db_transaction, block).await?;
+        }
+
+        db_transaction.commit().await?;
+        Ok(())
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -257,3 +384,12 @@ pub struct SQLiteTxOut {
     pub amount: i64,
 }
 
+#[derive(Clone, Debug)]
+pub struct SQLiteUnspentTxOut {
+    pub tx_out_id: String,
+    pub tx_out_index: i64,
+    pub address: String,
+    pub amount: i64,
+    pub is_coinbase: bool,
+    pub block_index: i64,
+}
@@ -2,7 +2,11 @@ pub mod block;
 pub mod block_chain;
 pub mod database;
 // pub mod dsa;
+pub mod miner;
 pub mod mylib;
+pub mod node;
+pub mod p2p;
 pub mod transaction;
+pub mod transaction_pool;
 pub mod wallet;
 pub mod websocket;
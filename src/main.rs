@@ -1,67 +1,217 @@
 use axum::{
     routing::{get, post},
     response::IntoResponse,
-    extract::State,
+    extract::{Query, State},
+    http::StatusCode,
     Router,
     Json,
 };
 use serde::Deserialize;
-use dotenv;
 use sqlx::sqlite::SqlitePool;
-use std::env;
+use std::{env, str::FromStr, thread};
 use blockchain_rust::{
-    block::Block,
     database::{
         Database,
         sqlite::SqliteDatabase,
     },
+    block::{Block, BlockSolution},
+    block_chain::ChainConfig,
+    node::Node,
+    p2p::P2P,
+    transaction::{
+        Transaction,
+        subsidy::SubsidySchedule,
+        utxo::COINBASE_MATURITY,
+    },
+    wallet::Wallet,
 };
 
+#[derive(Clone)]
+struct AppState<T: Database> {
+    node: Node<T>,
+    p2p: P2P<T>,
+}
+
+// Parses an optional setting. A value that is set but does not parse stops the node rather
+// than falling back to the default, which for consensus settings would split it from its
+// peers.
+fn env_var<T: FromStr>(key: &str) -> Option<T> {
+    let value = env::var(key).ok()?;
+    match value.parse::<T>() {
+        Ok(v) => Some(v),
+        Err(_) => panic!("Invalid {}: {:?}", key, value),
+    }
+}
+
 #[tokio::main]
 async fn main() {
     dotenv::dotenv().expect("Failed to read .env file");
     let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+    Wallet::initialize().expect("Failed to initialize the wallet");
+    let http_port = env::var("HTTP_PORT").unwrap_or("3000".to_string());
+    let p2p_port = env::var("P2P_PORT").unwrap_or("6001".to_string());
+    let mining_threads = env_var::<usize>("MINING_THREADS")
+        .or(thread::available_parallelism().ok().map(|v| v.get()))
+        .unwrap_or(1);
+    let default_schedule = SubsidySchedule::default();
+    let chain_config = ChainConfig {
+        subsidy_schedule: SubsidySchedule {
+            initial_subsidy: env_var("INITIAL_SUBSIDY").unwrap_or(default_schedule.initial_subsidy),
+            halving_interval: env_var("HALVING_INTERVAL").unwrap_or(default_schedule.halving_interval),
+            max_supply: env_var("MAX_SUPPLY").unwrap_or(default_schedule.max_supply),
+        },
+        coinbase_maturity: env_var("COINBASE_MATURITY").unwrap_or(COINBASE_MATURITY),
+    };
     let pool = SqlitePool::connect(&database_url).await.unwrap();
     let db = SqliteDatabase::new(pool);
+    let node = Node::load(db, chain_config, mining_threads).await.unwrap();
+    let p2p = P2P::new(node.clone(), format!("0.0.0.0:{}", p2p_port));
+    let p2p_listener = p2p.clone();
+    tokio::spawn(async move { p2p_listener.listen().await });
+    let state = AppState { node, p2p };
 
     let app = Router::new()
         .route("/blocks", get(blocks::<SqliteDatabase>))
+        .route("/cumulative_difficulty", get(cumulative_difficulty::<SqliteDatabase>))
+        .route("/supply", get(supply::<SqliteDatabase>))
         .route("/mine_block", post(mine_block::<SqliteDatabase>))
-        .route("/peers", get(peers))
-        .route("/add_peers", post(add_peers))
-        .route("/send_transaction", post(send_transaction))
-        .with_state(db);
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
+        .route("/mine_raw_block", post(mine_raw_block::<SqliteDatabase>))
+        .route("/block_template", get(block_template::<SqliteDatabase>))
+        .route("/submit_block", post(submit_block::<SqliteDatabase>))
+        .route("/mining_status", get(mining_status::<SqliteDatabase>))
+        .route("/stop_mining", post(stop_mining::<SqliteDatabase>))
+        .route("/peers", get(peers::<SqliteDatabase>))
+        .route("/add_peers", post(add_peers::<SqliteDatabase>))
+        .route("/send_transaction", post(send_transaction::<SqliteDatabase>))
+        .route("/transaction_pool", get(transaction_pool::<SqliteDatabase>))
+        .with_state(state);
+    let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{}", http_port)).await.unwrap();
 
     axum::serve(listener, app).await.unwrap();
 }
 
-async fn blocks<T: Database>(State(db): State<T>) -> impl IntoResponse {
-    let block_chain = db.find_block_chain().await.unwrap();
-    Json(block_chain)
+async fn blocks<T: Database>(State(state): State<AppState<T>>) -> impl IntoResponse {
+    Json(state.node.block_chain().await)
 }
 
-#[derive(Debug, Deserialize)]
-struct MineBlockRequest {
-    data: String,
+async fn cumulative_difficulty<T: Database>(State(state): State<AppState<T>>) -> impl IntoResponse {
+    Json(state.node.cumulative_difficulty().await)
+}
+
+async fn supply<T: Database>(State(state): State<AppState<T>>) -> impl IntoResponse {
+    Json(state.node.supply().await)
 }
 
-async fn mine_block<T: Database>(
-    State(db): State<T>,
-    Json(request): Json<MineBlockRequest>
+async fn mine_block<T: Database + Clone + Send + Sync + 'static>(
+    State(state): State<AppState<T>>,
 ) -> impl IntoResponse {
-    let mut block_chain = db.find_block_chain().await.unwrap();
-    let new_block = block_chain.append_new_block(vec!());
+    let new_block = match state.node.mine_block().await {
+        Ok(v) => v,
+        Err(message) => return (StatusCode::BAD_REQUEST, message).into_response(),
+    };
 
-    db.save_block(new_block.clone()).await.unwrap();
-    Json(new_block)
+    state.p2p.broadcast_latest().await;
+    Json(new_block).into_response()
 }
 
-async fn peers() {}
+#[derive(Deserialize)]
+struct MineRawBlockRequest {
+    data: Vec<Transaction>,
+}
 
-async fn add_peers() {}
+async fn mine_raw_block<T: Database + Clone + Send + Sync + 'static>(
+    State(state): State<AppState<T>>,
+    Json(request): Json<MineRawBlockRequest>,
+) -> impl IntoResponse {
+    let new_block = match state.node.mine_raw_block(request.data).await {
+        Ok(v) => v,
+        Err(message) => return (StatusCode::BAD_REQUEST, message).into_response(),
+    };
 
-async fn send_transaction() {
+    state.p2p.broadcast_latest().await;
+    Json(new_block).into_response()
+}
 
+#[derive(Deserialize)]
+struct BlockTemplateQuery {
+    address: String,
 }
 
+async fn block_template<T: Database>(
+    State(state): State<AppState<T>>,
+    Query(query): Query<BlockTemplateQuery>,
+) -> impl IntoResponse {
+    match state.node.block_template(query.address).await {
+        Ok(v) => Json(v).into_response(),
+        Err(message) => (StatusCode::BAD_REQUEST, message).into_response(),
+    }
+}
+
+// Accepts a template solved by an external miner
+async fn submit_block<T: Database + Clone + Send + Sync + 'static>(
+    State(state): State<AppState<T>>,
+    Json(solution): Json<BlockSolution>,
+) -> impl IntoResponse {
+    let block = Block::from_solution(solution);
+    if let Err(message) = state.node.add_block(block.clone()).await {
+        return (StatusCode::BAD_REQUEST, message).into_response()
+    }
+
+    state.p2p.broadcast_latest().await;
+    Json(block).into_response()
+}
+
+async fn mining_status<T: Database>(State(state): State<AppState<T>>) -> impl IntoResponse {
+    Json(state.node.mining_status())
+}
+
+async fn stop_mining<T: Database>(State(state): State<AppState<T>>) -> impl IntoResponse {
+    if !state.node.stop_mining() {
+        return (StatusCode::BAD_REQUEST, "Not mining").into_response()
+    }
+    Json(state.node.mining_status()).into_response()
+}
+
+async fn peers<T: Database + Clone + Send + Sync + 'static>(
+    State(state): State<AppState<T>>,
+) -> impl IntoResponse {
+    Json(state.p2p.peers())
+}
+
+#[derive(Debug, Deserialize)]
+struct AddPeersRequest {
+    peers: Vec<String>,
+}
+
+async fn add_peers<T: Database + Clone + Send + Sync + 'static>(
+    State(state): State<AppState<T>>,
+    Json(request): Json<AddPeersRequest>,
+) -> impl IntoResponse {
+    let mut errors = vec![];
+    for peer in request.peers.into_iter() {
+        if let Err(e) = state.p2p.connect(peer.clone()).await {
+            errors.push(format!("{}: {}", peer, e));
+        }
+    }
+
+    if !errors.is_empty() {
+        return (StatusCode::BAD_REQUEST, errors.join("\n")).into_response()
+    }
+    Json(state.p2p.peers()).into_response()
+}
+
+async fn send_transaction<T: Database + Clone + Send + Sync + 'static>(
+    State(state): State<AppState<T>>,
+    Json(transaction): Json<Transaction>,
+) -> impl IntoResponse {
+    if let Err(message) = state.node.add_transaction(transaction.clone()).await {
+        return (StatusCode::BAD_REQUEST, message).into_response()
+    }
+
+    state.p2p.broadcast_transaction_pool().await;
+    Json(transaction).into_response()
+}
+
+async fn transaction_pool<T: Database>(State(state): State<AppState<T>>) -> impl IntoResponse {
+    Json(state.node.transactions().await)
+}
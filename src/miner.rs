@@ -0,0 +1,120 @@
+use std::{
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    time::Instant,
+};
+use serde::Serialize;
+use crate::{
+    block::Block,
+    transaction::Transaction,
+};
+
+#[derive(Serialize)]
+pub struct MiningStatus {
+    pub is_mining: bool,
+    pub threads: usize,
+    // Hashes tried and hashes per second since the current or last search started
+    pub hashes: u64,
+    pub hashrate: f64,
+}
+
+#[derive(Default)]
+struct MinerState {
+    is_mining: AtomicBool,
+    is_cancelled: AtomicBool,
+    // Bumped whenever the chain tip changes, which makes the running search stale
+    tip_version: AtomicU64,
+    hashes: AtomicU64,
+    started_at: Mutex<Option<Instant>>,
+}
+
+// Runs the proof-of-work search on the blocking thread pool, so that it never holds up
+// the async runtime. Only one search runs at a time, split across `threads` threads.
+#[derive(Clone)]
+pub struct Miner {
+    state: Arc<MinerState>,
+    threads: usize,
+}
+
+// Marks the miner idle again when a mining request ends, however it ends
+pub struct MiningGuard<'a> {
+    miner: &'a Miner,
+}
+
+impl Drop for MiningGuard<'_> {
+    fn drop(&mut self) {
+        self.miner.state.is_mining.store(false, Ordering::SeqCst);
+    }
+}
+
+impl Miner {
+    pub fn new(threads: usize) -> Self {
+        Miner {
+            state: Arc::new(MinerState::default()),
+            threads: threads.max(1),
+        }
+    }
+
+    pub fn status(&self) -> MiningStatus {
+        let hashes = self.state.hashes.load(Ordering::Relaxed);
+        let elapsed = self.state.started_at.lock().unwrap()
+            .map(|started_at| started_at.elapsed().as_secs_f64())
+            .unwrap_or(0.0);
+        MiningStatus {
+            is_mining: self.state.is_mining.load(Ordering::SeqCst),
+            threads: self.threads,
+            hashes,
+            hashrate: if elapsed > 0.0 { hashes as f64 / elapsed } else { 0.0 },
+        }
+    }
+
+    pub fn start(&self) -> Result<MiningGuard<'_>, String> {
+        if self.state.is_mining.swap(true, Ordering::SeqCst) {
+            return Err("Already mining".to_string())
+        }
+        self.state.is_cancelled.store(false, Ordering::SeqCst);
+        self.state.hashes.store(0, Ordering::Relaxed);
+        *self.state.started_at.lock().unwrap() = Some(Instant::now());
+        Ok(MiningGuard { miner: self })
+    }
+
+    // Stops the running search, if any. Returns whether one was running.
+    pub fn cancel(&self) -> bool {
+        self.state.is_cancelled.store(true, Ordering::SeqCst);
+        self.state.is_mining.load(Ordering::SeqCst)
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.state.is_cancelled.load(Ordering::SeqCst)
+    }
+
+    pub fn tip_version(&self) -> u64 {
+        self.state.tip_version.load(Ordering::SeqCst)
+    }
+
+    pub fn tip_changed(&self) {
+        self.state.tip_version.fetch_add(1, Ordering::SeqCst);
+    }
+
+    // Searches for a block on top of `latest_block`. Returns None when the search was
+    // cancelled, or when the tip moved past `tip_version` and the search has to restart.
+    pub async fn find_block(
+        &self,
+        latest_block: Block,
+        data: Vec<Transaction>,
+        difficulty: u32,
+        tip_version: u64,
+    ) -> Result<Option<Block>, String> {
+        let miner = self.clone();
+        tokio::task::spawn_blocking(move || {
+            latest_block.find_block(data, difficulty, miner.threads, |hashes| {
+                miner.state.hashes.fetch_add(hashes, Ordering::Relaxed);
+                miner.is_cancelled() || miner.tip_version() != tip_version
+            })
+        })
+        .await
+        .map_err(|e| e.to_string())
+    }
+}
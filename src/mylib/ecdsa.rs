@@ -18,40 +18,62 @@ pub fn generate_private_key() -> String {
 }
 
 pub fn public_key_from_private_key(private_key: String) -> Result<String, String> {
-    match SigningKey::from_slice(&decode_hex(private_key)) {
+    match SigningKey::from_slice(&decode_hex(private_key)?) {
         Ok(signing_key) => {
             let verifying_key = signing_key.verifying_key();
-            return Ok(encode_hex(&verifying_key.to_encoded_point(false).to_bytes()))
+            Ok(encode_hex(&verifying_key.to_encoded_point(false).to_bytes()))
         },
         Err(error) => {
-            return Err(error.to_string())
+            Err(error.to_string())
         }
-    };
+    }
 }
 
 pub fn sign(private_key: String, data: String) -> Result<String, String> {
-    match SigningKey::from_slice(&decode_hex(private_key)) {
+    match SigningKey::from_slice(&decode_hex(private_key)?) {
         Ok(signing_key) => {
-            let signature: Signature = signing_key.sign(&data.into_bytes());
-            return Ok(encode_hex(&signature.to_bytes()))
+            let signature: Signature = signing_key.sign(&decode_hex(data)?);
+            Ok(encode_hex(&signature.to_bytes()))
         },
         Err(error) => {
-            return Err(error.to_string())
+            Err(error.to_string())
         }
-    };
+    }
 }
 
 pub fn verify(public_key: String, transaction_id: String, signature: String) -> Result<(), String> {
-    let verifying_key = match VerifyingKey::from_sec1_bytes(&decode_hex(public_key)) {
+    let verifying_key = match VerifyingKey::from_sec1_bytes(&decode_hex(public_key)?) {
         Err(error) => return Err(error.to_string()),
         Ok(v) => v,
     };
-    let signature = match Signature::from_slice(&decode_hex(signature)) {
+    let signature = match Signature::from_slice(&decode_hex(signature)?) {
         Err(error) => return Err(error.to_string()),
         Ok(v) => v,
     };
-    match verifying_key.verify(&decode_hex(transaction_id), &signature) {
+    match verifying_key.verify(&decode_hex(transaction_id)?, &signature) {
         Ok(()) => Ok(()),
         Err(error) => Err(error.to_string())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRANSACTION_ID: &str = "8d0e8b2d6c1ff4c8e4b1c2b5a0e7f3a9d6c4b2a1908f7e6d5c4b3a2918f7e6d5";
+
+    #[test]
+    fn signature_over_transaction_id_verifies() {
+        let private_key = generate_private_key();
+        let public_key = public_key_from_private_key(private_key.clone()).unwrap();
+        let signature = sign(private_key, TRANSACTION_ID.to_string()).unwrap();
+        assert_eq!(verify(public_key, TRANSACTION_ID.to_string(), signature), Ok(()));
+    }
+
+    #[test]
+    fn signature_does_not_verify_for_another_key() {
+        let public_key = public_key_from_private_key(generate_private_key()).unwrap();
+        let signature = sign(generate_private_key(), TRANSACTION_ID.to_string()).unwrap();
+        assert!(verify(public_key, TRANSACTION_ID.to_string(), signature).is_err());
+    }
+}
@@ -2,9 +2,16 @@ pub fn encode_hex(bytes: &[u8]) -> String {
     bytes.iter().map(|n| format!("{:02X}", n)).collect::<String>()
 }
 
-pub fn decode_hex(hex: String) -> Vec<u8> {
+pub fn decode_hex(hex: String) -> Result<Vec<u8>, String> {
+    if !hex.len().is_multiple_of(2) {
+        return Err("Hex string must have an even length".to_string())
+    }
     (0..hex.as_str().len())
         .step_by(2)
-        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
-        .collect::<Vec<u8>>()
+        .map(|i| {
+            hex.get(i..i + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                .ok_or("Invalid hex string".to_string())
+        })
+        .collect::<Result<Vec<u8>, String>>()
 }
@@ -0,0 +1,181 @@
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use crate::{
+    block::{Block, BlockTemplate},
+    block_chain::{BlockChain, ChainConfig},
+    database::Database,
+    miner::{Miner, MiningStatus},
+    transaction::{
+        Transaction,
+        subsidy::Supply,
+    },
+    transaction_pool::TransactionPool,
+    wallet::Wallet,
+};
+
+// State shared by the HTTP API and the P2P layer. The block chain lock is held while a
+// block is persisted, so the in-memory chain and the database never diverge.
+#[derive(Clone)]
+pub struct Node<T: Database> {
+    db: T,
+    block_chain: Arc<Mutex<BlockChain>>,
+    transaction_pool: Arc<Mutex<TransactionPool>>,
+    miner: Miner,
+}
+
+impl<T: Database> Node<T> {
+    pub async fn load(db: T, config: ChainConfig, mining_threads: usize) -> Result<Self, sqlx::Error> {
+        let block_chain = db.find_block_chain(config).await?;
+        Ok(Node {
+            db,
+            block_chain: Arc::new(Mutex::new(block_chain)),
+            transaction_pool: Arc::new(Mutex::new(TransactionPool::new())),
+            miner: Miner::new(mining_threads),
+        })
+    }
+
+    pub async fn block_chain(&self) -> BlockChain {
+        self.block_chain.lock().await.clone()
+    }
+
+    pub async fn blocks(&self) -> Vec<Block> {
+        self.block_chain.lock().await.blocks().to_vec()
+    }
+
+    pub async fn cumulative_difficulty(&self) -> u128 {
+        self.block_chain.lock().await.cumulative_difficulty()
+    }
+
+    pub async fn latest_block(&self) -> Block {
+        self.block_chain.lock().await.latest_block()
+    }
+
+    pub async fn supply(&self) -> Supply {
+        let block_chain = self.block_chain.lock().await;
+        Supply::at(&block_chain.config().subsidy_schedule, block_chain.latest_block().index())
+    }
+
+    pub async fn transactions(&self) -> Vec<Transaction> {
+        self.transaction_pool.lock().await.transactions()
+    }
+
+    // Mines a block paying the reward and fees to the node wallet. Pooled transactions are
+    // picked by fee per byte, highest first, up to MAX_BLOCK_TRANSACTIONS_SIZE.
+    pub async fn mine_block(&self) -> Result<Block, String> {
+        let address = Wallet::public_key()?;
+        self.mine(|block_chain, transaction_pool| {
+            Self::block_data(block_chain, address.clone(), transaction_pool)
+        }).await
+    }
+
+    // Mines a block from the given transactions, coinbase included
+    pub async fn mine_raw_block(&self, data: Vec<Transaction>) -> Result<Block, String> {
+        self.mine(|_, _| data.clone()).await
+    }
+
+    // The block an external miner should search for, paying the reward to `address`
+    pub async fn block_template(&self, address: String) -> Result<BlockTemplate, String> {
+        let block_chain = self.block_chain.lock().await;
+        let latest_block = block_chain.latest_block();
+        let data = Self::block_data(&block_chain, address, &*self.transaction_pool.lock().await);
+        Block::validate_transactions(latest_block.index() + 1, &data, block_chain.utxo_set(), block_chain.config())?;
+        Ok(latest_block.template(data, block_chain.adjusted_difficulty()))
+    }
+
+    pub fn mining_status(&self) -> MiningStatus {
+        self.miner.status()
+    }
+
+    // Returns whether a search was running
+    pub fn stop_mining(&self) -> bool {
+        self.miner.cancel()
+    }
+
+    pub async fn add_block(&self, block: Block) -> Result<(), String> {
+        let mut block_chain = self.block_chain.lock().await;
+        block_chain.validate_block(&block)?;
+
+        self.db.save_block(block.clone()).await.map_err(|e| e.to_string())?;
+        block_chain.append_block(block);
+        self.miner.tip_changed();
+        self.update_transaction_pool(&block_chain).await;
+        Ok(())
+    }
+
+    pub async fn replace_chain(&self, blocks: Vec<Block>) -> Result<(), String> {
+        let mut block_chain = self.block_chain.lock().await;
+        let new_chain = BlockChain::new(blocks, block_chain.config().clone());
+        let new_block_chain = block_chain.validate_replacement(new_chain)?;
+
+        self.db.replace_block_chain(&new_block_chain).await.map_err(|e| e.to_string())?;
+        *block_chain = new_block_chain;
+        self.miner.tip_changed();
+        self.update_transaction_pool(&block_chain).await;
+        Ok(())
+    }
+
+    pub async fn add_transaction(&self, transaction: Transaction) -> Result<(), String> {
+        let block_chain = self.block_chain.lock().await;
+        self.transaction_pool.lock().await.add_transaction(
+            transaction,
+            block_chain.utxo_set(),
+            block_chain.latest_block().index() + 1,
+            block_chain.config().coinbase_maturity,
+        )
+    }
+
+    // Searches for a block on top of the current tip with the data `build_data` returns for
+    // the chain and the transaction pool. The search runs off the async runtime and starts
+    // over whenever the tip changes under it.
+    async fn mine(&self, build_data: impl Fn(&BlockChain, &TransactionPool) -> Vec<Transaction>) -> Result<Block, String> {
+        let _guard = self.miner.start()?;
+        loop {
+            let (latest_block, data, difficulty, tip_version) = {
+                let block_chain = self.block_chain.lock().await;
+                let latest_block = block_chain.latest_block();
+                let data = build_data(&block_chain, &*self.transaction_pool.lock().await);
+                Block::validate_transactions(
+                    latest_block.index() + 1,
+                    &data,
+                    block_chain.utxo_set(),
+                    block_chain.config(),
+                )?;
+                (latest_block, data, block_chain.adjusted_difficulty(), self.miner.tip_version())
+            };
+
+            let new_block = match self.miner.find_block(latest_block, data, difficulty, tip_version).await? {
+                Some(v) => v,
+                None if self.miner.is_cancelled() => return Err("Mining was cancelled".to_string()),
+                None => continue,
+            };
+            match self.add_block(new_block.clone()).await {
+                Ok(()) => return Ok(new_block),
+                // Another block took the tip just before ours was found
+                Err(_) if self.miner.tip_version() != tip_version => continue,
+                Err(message) => return Err(message),
+            }
+        }
+    }
+
+    // A coinbase paying the reward and fees to `address`, followed by the pooled
+    // transactions that pay the most per byte
+    fn block_data(block_chain: &BlockChain, address: String, transaction_pool: &TransactionPool) -> Vec<Transaction> {
+        let transactions = transaction_pool.select_transactions(block_chain.utxo_set());
+        let total_fee = Transaction::total_fee(&transactions, block_chain.utxo_set());
+        let coinbase_transaction = Transaction::coinbase(
+            address,
+            block_chain.latest_block().index() + 1,
+            total_fee,
+            &block_chain.config().subsidy_schedule,
+        );
+        [vec![coinbase_transaction], transactions].concat()
+    }
+
+    async fn update_transaction_pool(&self, block_chain: &BlockChain) {
+        self.transaction_pool.lock().await.update(
+            block_chain.utxo_set(),
+            block_chain.latest_block().index() + 1,
+            block_chain.config().coinbase_maturity,
+        );
+    }
+}
@@ -0,0 +1,195 @@
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use crate::{
+    block::Block,
+    database::Database,
+    node::Node,
+    transaction::Transaction,
+    websocket::{
+        OperationResult,
+        WebSocket,
+        WebSocketContext,
+        close_code::CloseCode,
+        frame::Frame,
+        handle::{ConnectionId, WebSocketHandle},
+        opcode::Opcode,
+    },
+};
+
+const PROTOCOL: &str = "blockchain-v1";
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+const MAX_MISSED_PONGS: u32 = 3;
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", content = "data", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum Message {
+    QueryLatest,
+    QueryAll,
+    // Either the latest block or the whole chain, along with the cumulative difficulty of
+    // the sender's chain so that the receiver can compare work before asking for more
+    ResponseBlockchain {
+        blocks: Vec<Block>,
+        cumulative_difficulty: u128,
+    },
+    QueryTransactionPool,
+    ResponseTransactionPool(Vec<Transaction>),
+}
+
+#[derive(Clone)]
+pub struct P2P<T: Database> {
+    node: Node<T>,
+    endpoint: String,
+    // Every open connection, whether the peer dialed us or we dialed it
+    handle: WebSocketHandle,
+}
+
+impl<T: Database + Clone + Send + Sync + 'static> P2P<T> {
+    pub fn new(node: Node<T>, endpoint: String) -> Self {
+        P2P {
+            node,
+            endpoint,
+            handle: WebSocketHandle::new(),
+        }
+    }
+
+    pub async fn listen(&self) {
+        self.websocket().listen().await;
+    }
+
+    pub async fn connect(&self, url: String) -> Result<(), String> {
+        self.websocket().connect(url).await.map(|_| ())
+    }
+
+    pub fn peers(&self) -> Vec<String> {
+        self.handle.connections()
+            .into_iter()
+            .map(|(_, address)| address)
+            .collect()
+    }
+
+    pub fn broadcast(&self, message: Message) {
+        self.handle.broadcast(Self::message_to_frame(message));
+    }
+
+    pub async fn broadcast_latest(&self) {
+        self.broadcast(self.response_latest().await);
+    }
+
+    pub async fn broadcast_transaction_pool(&self) {
+        let transactions = self.node.transactions().await;
+        self.broadcast(Message::ResponseTransactionPool(transactions));
+    }
+
+    async fn response_latest(&self) -> Message {
+        Message::ResponseBlockchain {
+            blocks: vec![self.node.latest_block().await],
+            cumulative_difficulty: self.node.cumulative_difficulty().await,
+        }
+    }
+
+    fn websocket(&self) -> WebSocket {
+        let p2p = self.clone();
+        let handle_context = move |context| {
+            let p2p = p2p.clone();
+            async move { p2p.handle_context(context).await }
+        };
+        let handle = self.handle.clone();
+        WebSocket::builder()
+            .endpoint(self.endpoint.clone())
+            .handle(self.handle.clone())
+            .protocol(PROTOCOL.to_string())
+            .permessage_deflate()
+            .ping_interval(PING_INTERVAL)
+            .max_missed_pongs(MAX_MISSED_PONGS)
+            // Catch up with a new peer, whichever side opened the connection
+            .on_connect(move |connection_id, _| {
+                Self::send(&handle, connection_id, Message::QueryLatest);
+                Self::send(&handle, connection_id, Message::QueryTransactionPool);
+            })
+            .add_operation(Opcode::Text, handle_context.clone())
+            .add_operation(Opcode::Binary, handle_context)
+            .build()
+    }
+
+    fn send(handle: &WebSocketHandle, connection_id: ConnectionId, message: Message) {
+        let _ = handle.send_to(connection_id, Self::message_to_frame(message));
+    }
+
+    fn message_to_frame(message: Message) -> Frame {
+        let payload = serde_json::to_vec(&message).unwrap();
+        Frame::new(Opcode::Text, Some(payload))
+    }
+
+    async fn handle_context(&self, context: WebSocketContext) -> OperationResult {
+        let message = match serde_json::from_slice::<Message>(&context.frame.payload_data()) {
+            Ok(v) => v,
+            Err(_) => return OperationResult::Close(CloseCode::UnsupportedData, "Invalid message".to_string()),
+        };
+        match self.handle_message(context.connection_id, message).await {
+            Some(reply) => OperationResult::Reply(Self::message_to_frame(reply)),
+            None => OperationResult::Continue,
+        }
+    }
+
+    // Returns the reply to send back to the peer, if any
+    async fn handle_message(&self, connection_id: ConnectionId, message: Message) -> Option<Message> {
+        match message {
+            Message::QueryLatest => Some(self.response_latest().await),
+            Message::QueryAll => Some(Message::ResponseBlockchain {
+                blocks: self.node.blocks().await,
+                cumulative_difficulty: self.node.cumulative_difficulty().await,
+            }),
+            Message::ResponseBlockchain { blocks, cumulative_difficulty } => {
+                self.handle_blockchain_response(connection_id, blocks, cumulative_difficulty).await;
+                None
+            },
+            Message::QueryTransactionPool => {
+                let transactions = self.node.transactions().await;
+                Some(Message::ResponseTransactionPool(transactions))
+            },
+            Message::ResponseTransactionPool(transactions) => {
+                let mut is_added = false;
+                for transaction in transactions.into_iter() {
+                    is_added |= self.node.add_transaction(transaction).await.is_ok();
+                }
+                if is_added {
+                    self.broadcast_transaction_pool().await;
+                }
+                None
+            },
+        }
+    }
+
+    async fn handle_blockchain_response(
+        &self,
+        connection_id: ConnectionId,
+        blocks: Vec<Block>,
+        cumulative_difficulty: u128,
+    ) {
+        let latest_block_received = match blocks.last() {
+            Some(v) => v.clone(),
+            None => return,
+        };
+        let latest_block_held = self.node.latest_block().await;
+        if latest_block_received.hash() == latest_block_held.hash() {
+            return
+        }
+        // Point a peer with less work at our chain. On equal work replace_chain breaks the
+        // tie, so both sides still need to see the other's chain.
+        if cumulative_difficulty < self.node.cumulative_difficulty().await {
+            Self::send(&self.handle, connection_id, self.response_latest().await);
+            return
+        }
+
+        if latest_block_held.hash() == latest_block_received.previous_hash() {
+            if self.node.add_block(latest_block_received).await.is_ok() {
+                self.broadcast_latest().await;
+            }
+        } else if blocks.len() == 1 {
+            // The sender's chain does not simply extend ours, so ask for all of it
+            Self::send(&self.handle, connection_id, Message::QueryAll);
+        } else if self.node.replace_chain(blocks).await.is_ok() {
+            self.broadcast_latest().await;
+        }
+    }
+}
@@ -1,22 +1,24 @@
+pub mod subsidy;
 pub mod tx_out;
 pub mod tx_in;
 pub mod utxo;
+pub mod utxo_set;
 use std::fmt;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use sha256::digest;
 use crate::transaction::{
+    subsidy::SubsidySchedule,
     tx_in::TxIn,
     tx_out::TxOut,
     utxo::UTxO,
+    utxo_set::UtxoSet,
 };
 use crate::mylib::ecdsa::{
     public_key_from_private_key,
     sign,
 };
 
-const COINBASE_AMOUNT: u32 = 50;
-
-#[derive(Clone, Serialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Transaction {
     id: String,
     tx_in_list: Vec<TxIn>,
@@ -37,7 +39,29 @@ impl Transaction {
         }
     }
 
+    // The first transaction of a block, minting the block subsidy and claiming the fees of
+    // the other transactions. Its single TxIn refers to no output; it carries the block
+    // height so that coinbases of different blocks get different ids.
+    pub fn coinbase(address: String, block_index: u32, total_fee: u32, schedule: &SubsidySchedule) -> Self {
+        Self::new(
+            None,
+            vec![TxIn::new("".to_string(), block_index as usize, "".to_string())],
+            vec![TxOut::new(address, schedule.block_subsidy(block_index).saturating_add(total_fee))],
+        )
+    }
+
     pub fn is_valid(&self) -> Result<(), &'static str> {
+        if self.tx_in_list.is_empty() || self.tx_out_list.is_empty() {
+            return Err("Transaction must have at least one TxIn and one TxOut")
+        }
+        for (index, tx_in) in self.tx_in_list.iter().enumerate() {
+            if self.tx_in_list[..index].iter().any(|other| {
+                other.tx_out_id() == tx_in.tx_out_id()
+                && other.tx_out_index() == tx_in.tx_out_index()
+            }) {
+                return Err("Duplicate TxIn in transaction")
+            }
+        }
         if self.id() != self.transaction_id() {
             return Err("Invalid transaction id")
         }
@@ -45,33 +69,92 @@ impl Transaction {
         Ok(())
     }
 
-    pub fn check_amount_between_in_and_out(&self, utxo_list: Vec<UTxO>) -> Result<(), &'static str> {
-        let total_tx_in_amount = self.tx_in_list
+    // The TxIn total may exceed the TxOut total; the difference is the fee
+    pub fn check_amount_between_in_and_out(&self, utxo_set: &UtxoSet) -> Result<(), &'static str> {
+        if self.total_tx_in_amount(utxo_set) < self.total_tx_out_amount() {
+            return Err("Total value of TxOut exceeds that of TxIn")
+        }
+
+        Ok(())
+    }
+
+    // Coinbase outputs may only be spent once the coinbase maturity has passed
+    pub fn check_maturity(
+        &self,
+        utxo_set: &UtxoSet,
+        block_index: u32,
+        coinbase_maturity: u32,
+    ) -> Result<(), &'static str> {
+        let is_immature = self.tx_in_list.iter().any(|tx_in| {
+            utxo_set.find(&tx_in.tx_out_id(), tx_in.tx_out_index())
+                .is_some_and(|utxo| !utxo.is_mature(block_index, coinbase_maturity))
+        });
+        if is_immature {
+            return Err("TxIn spends an immature coinbase output")
+        }
+
+        Ok(())
+    }
+
+    pub fn fee(&self, utxo_set: &UtxoSet) -> u32 {
+        let fee = self.total_tx_in_amount(utxo_set).saturating_sub(self.total_tx_out_amount());
+        u32::try_from(fee).unwrap_or(u32::MAX)
+    }
+
+    // Sum of the fees of `transactions`, which the coinbase of their block may claim
+    pub fn total_fee(transactions: &[Transaction], utxo_set: &UtxoSet) -> u32 {
+        transactions.iter()
+            .map(|transaction| transaction.fee(utxo_set))
+            .fold(0, |a, b| a.saturating_add(b))
+    }
+
+    // Size of the transaction on the wire, used to rank transactions by fee per byte
+    pub fn size(&self) -> usize {
+        serde_json::to_vec(self).map(|v| v.len()).unwrap_or(0)
+    }
+
+    fn total_tx_in_amount(&self, utxo_set: &UtxoSet) -> u64 {
+        self.tx_in_list
             .iter()
             .map(|tx_in| {
-                match utxo_list.iter().find(|utxo| {
-                    utxo.tx_out_id() == tx_in.tx_out_id()
-                    && utxo.tx_out_index() == tx_in.tx_out_index()
-                }) {
-                    Some(tx_out) => tx_out.amount(),
+                match utxo_set.find(&tx_in.tx_out_id(), tx_in.tx_out_index()) {
+                    Some(utxo) => utxo.amount() as u64,
                     None => 0,
                 }
             })
-            .reduce(|a, b| a + b);
+            .sum()
+    }
 
-        let total_tx_out_amount = self.tx_out_list
+    fn total_tx_out_amount(&self) -> u64 {
+        self.tx_out_list
             .iter()
-            .map(|tx_out| tx_out.amount())
-            .reduce(|a, b| a + b);
-
-        if total_tx_in_amount != total_tx_out_amount {
-            return Err("Total values of TxIn and TxOut do not match")
-        }
+            .map(|tx_out| tx_out.amount() as u64)
+            .sum()
+    }
 
-        Ok(())
+    // Signs a transaction paying every output in `utxo_list` to `address`, without a fee
+    #[cfg(test)]
+    pub fn signed(utxo_list: &[UTxO], private_key: String, address: String) -> Self {
+        let amount = utxo_list.iter().map(|utxo| utxo.amount()).sum();
+        let tx_in_list = utxo_list.iter()
+            .map(|utxo| TxIn::new(utxo.tx_out_id(), utxo.tx_out_index(), "".to_string()))
+            .collect::<Vec<TxIn>>();
+        let transaction = Self::new(None, tx_in_list, vec![TxOut::new(address, amount)]);
+        let tx_in_list = transaction.tx_in_list().iter().enumerate()
+            .map(|(index, tx_in)| {
+                let signature = transaction.sign_tx_in(index as u32, private_key.clone(), utxo_list).unwrap();
+                TxIn::new(tx_in.tx_out_id(), tx_in.tx_out_index(), signature)
+            })
+            .collect::<Vec<TxIn>>();
+        Self::new(Some(transaction.id()), tx_in_list, transaction.tx_out_list())
     }
 
-    pub fn is_coinbase(&self, block_index: usize) -> Result<(), &'static str> {
+    pub fn is_coinbase(
+        &self,
+        block_index: usize,
+        total_fee: u32,
+        schedule: &SubsidySchedule,
+    ) -> Result<(), &'static str> {
         if self.tx_in_list().len() != 1 {
             return Err("One TxIn must be specified in the coinbase transaction")
         }
@@ -81,7 +164,7 @@ impl Transaction {
         if self.tx_out_list().len() != 1 {
             return Err("Invalid number of TxOutList in coinbase transaction")
         }
-        if self.tx_out_list()[0].amount() != COINBASE_AMOUNT {
+        if self.tx_out_list()[0].amount() != schedule.block_subsidy(block_index as u32).saturating_add(total_fee) {
             return Err("Invalid coinbase amount in coinbase transaction")
         }
         Ok(())
@@ -94,13 +177,11 @@ impl Transaction {
     pub fn calculate_transaction_id(tx_in_list: Vec<TxIn>, tx_out_list: Vec<TxOut>) -> String {
         let tx_in_content = tx_in_list.iter()
             .map(|tx_in| format!("{}{}", tx_in.tx_out_id(), tx_in.tx_out_index()))
-            .reduce(|a, b| format!("{}{}", a, b))
-            .unwrap();
+            .collect::<String>();
 
         let tx_out_content = tx_out_list.iter()
             .map(|tx_out| format!("{}{}", tx_out.address(), tx_out.amount()))
-            .reduce(|a, b| format!("{}{}", a, b))
-            .unwrap();
+            .collect::<String>();
 
         digest(format!("{}{}", tx_in_content, tx_out_content).to_string())
     }
@@ -108,7 +189,7 @@ impl Transaction {
     pub fn sign_tx_in(&self,
         tx_in_index: u32,
         private_key: String,
-        utxo_list: Vec<UTxO>,
+        utxo_list: &[UTxO],
     ) -> Result<String, String> {
         let tx_in = self.tx_in_list[tx_in_index as usize].clone();
         let data_to_sign = self.id.clone();
@@ -132,13 +213,15 @@ impl Transaction {
         sign(private_key, data_to_sign)
     }
 
-    pub fn new_utxo(&self) -> Vec<UTxO> {
+    pub fn new_utxo(&self, is_coinbase: bool, block_index: u32) -> Vec<UTxO> {
         self.tx_out_list().iter().enumerate().map(|(index, tx_out)| {
             UTxO::new(
                 self.id(),
                 index,
                 tx_out.address(),
                 tx_out.amount(),
+                is_coinbase,
+                block_index,
             )
         })
         .collect::<Vec<UTxO>>()
@@ -153,6 +236,8 @@ impl Transaction {
                     tx_in.tx_out_index(),
                     "".to_string(),
                     0,
+                    false,
+                    0,
                 )
             })
             .collect::<Vec<UTxO>>()
@@ -166,13 +251,11 @@ impl fmt::Display for Transaction {
             self.tx_in_list()
                 .iter()
                 .map(|v| format!("{}", v))
-                .reduce(|a, b| format!("{}{}", a, b))
-                .unwrap(),
+                .collect::<String>(),
             self.tx_out_list()
                 .iter()
                 .map(|v| format!("{}", v))
-                .reduce(|a, b| format!("{}{}", a, b))
-                .unwrap(),
+                .collect::<String>(),
         )
     }
 }
@@ -0,0 +1,139 @@
+use serde::Serialize;
+
+// The default issuance schedule. Every block after the genesis block mints a subsidy,
+// starting at COINBASE_AMOUNT and halving every HALVING_INTERVAL blocks. No subsidy is paid
+// once MAX_SUPPLY has been issued.
+pub const COINBASE_AMOUNT: u32 = 50;
+pub const HALVING_INTERVAL: u32 = 210_000;
+pub const MAX_SUPPLY: u64 = 21_000_000;
+
+// The issuance schedule blocks are validated against. Every node of a network has to use
+// the same one.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SubsidySchedule {
+    pub initial_subsidy: u32,
+    pub halving_interval: u32,
+    pub max_supply: u64,
+}
+
+impl Default for SubsidySchedule {
+    fn default() -> Self {
+        SubsidySchedule {
+            initial_subsidy: COINBASE_AMOUNT,
+            halving_interval: HALVING_INTERVAL,
+            max_supply: MAX_SUPPLY,
+        }
+    }
+}
+
+impl SubsidySchedule {
+    // The subsidy the coinbase of the block at `block_index` may mint
+    pub fn block_subsidy(&self, block_index: u32) -> u32 {
+        if block_index == 0 {
+            return 0
+        }
+        (self.issued_supply(block_index) - self.issued_supply(block_index - 1)) as u32
+    }
+
+    // Total subsidy minted by the blocks up to and including `block_index`
+    pub fn issued_supply(&self, block_index: u32) -> u64 {
+        let halving_interval = self.halving_interval.max(1) as u64;
+        let mut supply = 0;
+        // The genesis block has no coinbase
+        let mut era_start = 1;
+        while era_start <= block_index as u64 && supply < self.max_supply {
+            let era = era_start / halving_interval;
+            let subsidy = self.initial_subsidy.checked_shr(era as u32).unwrap_or(0) as u64;
+            if subsidy == 0 {
+                break
+            }
+            let era_end = ((era + 1) * halving_interval - 1).min(block_index as u64);
+            supply += subsidy * (era_end - era_start + 1);
+            era_start = era_end + 1;
+        }
+        supply.min(self.max_supply)
+    }
+}
+
+#[derive(Serialize)]
+pub struct Supply {
+    pub height: u32,
+    pub issued_supply: u64,
+    pub max_supply: u64,
+    pub next_block_subsidy: u32,
+}
+
+impl Supply {
+    pub fn at(schedule: &SubsidySchedule, height: u32) -> Self {
+        Supply {
+            height,
+            issued_supply: schedule.issued_supply(height),
+            max_supply: schedule.max_supply,
+            next_block_subsidy: schedule.block_subsidy(height.saturating_add(1)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Small enough for the cap to bind during the second era: blocks 1 to 9 mint 450, and
+    // blocks 10 to 15 mint the last 150
+    const CAPPED: SubsidySchedule = SubsidySchedule {
+        initial_subsidy: 50,
+        halving_interval: 10,
+        max_supply: 600,
+    };
+
+    #[test]
+    fn genesis_block_mints_nothing() {
+        assert_eq!(SubsidySchedule::default().block_subsidy(0), 0);
+        assert_eq!(SubsidySchedule::default().issued_supply(0), 0);
+    }
+
+    #[test]
+    fn subsidy_halves_at_the_interval_boundaries() {
+        let schedule = SubsidySchedule::default();
+        assert_eq!(schedule.block_subsidy(1), 50);
+        assert_eq!(schedule.block_subsidy(HALVING_INTERVAL - 1), 50);
+        assert_eq!(schedule.block_subsidy(HALVING_INTERVAL), 25);
+        assert_eq!(schedule.block_subsidy(2 * HALVING_INTERVAL - 1), 25);
+        assert_eq!(schedule.block_subsidy(2 * HALVING_INTERVAL), 12);
+
+        assert_eq!(schedule.issued_supply(HALVING_INTERVAL - 1), 50 * (HALVING_INTERVAL as u64 - 1));
+        assert_eq!(
+            schedule.issued_supply(HALVING_INTERVAL),
+            50 * (HALVING_INTERVAL as u64 - 1) + 25,
+        );
+    }
+
+    #[test]
+    fn default_schedule_stays_under_the_cap() {
+        let schedule = SubsidySchedule::default();
+        assert_eq!(schedule.block_subsidy(u32::MAX), 0);
+        assert!(schedule.issued_supply(u32::MAX) < MAX_SUPPLY);
+    }
+
+    #[test]
+    fn subsidy_stops_at_the_cap() {
+        assert_eq!(CAPPED.block_subsidy(9), 50);
+        assert_eq!(CAPPED.block_subsidy(10), 25);
+        assert_eq!(CAPPED.issued_supply(14), 575);
+
+        assert_eq!(CAPPED.block_subsidy(15), 25);
+        assert_eq!(CAPPED.issued_supply(15), 600);
+        assert_eq!(CAPPED.block_subsidy(16), 0);
+        assert_eq!(CAPPED.issued_supply(16), 600);
+        assert_eq!(CAPPED.issued_supply(u32::MAX), 600);
+    }
+
+    #[test]
+    fn last_subsidy_is_cut_to_the_cap() {
+        let schedule = SubsidySchedule { max_supply: 610, ..CAPPED };
+        assert_eq!(schedule.block_subsidy(15), 25);
+        assert_eq!(schedule.block_subsidy(16), 10);
+        assert_eq!(schedule.block_subsidy(17), 0);
+        assert_eq!(schedule.issued_supply(17), 610);
+    }
+}
@@ -1,9 +1,9 @@
 use std::fmt;
-use serde::Serialize;
-use crate::transaction::utxo::UTxO;
+use serde::{Deserialize, Serialize};
+use crate::transaction::utxo_set::UtxoSet;
 use crate::mylib::ecdsa::verify;
 
-#[derive(Clone, Serialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct TxIn {
     tx_out_id: String,
     tx_out_index: usize,
@@ -19,14 +19,8 @@ impl TxIn {
         TxIn { tx_out_id, tx_out_index, signature }
     }
 
-    pub fn is_valid(&self, transaction: super::Transaction, utxo_list: Vec<UTxO>) -> Result<(), String> {
-        let referenced_unspent_tx_out = match utxo_list
-            .iter()
-            .find(|utxo| {
-                utxo.tx_out_id() == self.tx_out_id()
-                && utxo.tx_out_index() == self.tx_out_index()
-            })
-        {
+    pub fn is_valid(&self, transaction: &super::Transaction, utxo_set: &UtxoSet) -> Result<(), String> {
+        let referenced_unspent_tx_out = match utxo_set.find(&self.tx_out_id, self.tx_out_index) {
             Some(v) => v,
             None => return Err("Failed to find referenced UTxO".to_string()),
         };
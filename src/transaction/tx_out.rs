@@ -1,7 +1,7 @@
 use std::fmt;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
-#[derive(Clone, Serialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct TxOut {
     address: String,
     amount: u32,
@@ -1,8 +1,16 @@
+// Default number of blocks a coinbase output waits for before it can be spent. A fork
+// that drops the minting block would also drop the coins, along with every spend of them.
+pub const COINBASE_MATURITY: u32 = 100;
+
+#[derive(Clone)]
 pub struct UTxO {
     tx_out_id: String,
     tx_out_index: usize,
     address: String,
     amount: u32,
+    is_coinbase: bool,
+    // Index of the block that created the output
+    block_index: u32,
 }
 
 impl UTxO {
@@ -10,18 +18,29 @@ impl UTxO {
     pub fn tx_out_index(&self) -> usize { self.tx_out_index }
     pub fn address(&self) -> String { self.address.clone() }
     pub fn amount(&self) -> u32 { self.amount }
+    pub fn is_coinbase(&self) -> bool { self.is_coinbase }
+    pub fn block_index(&self) -> u32 { self.block_index }
 
     pub fn new(
         tx_out_id: String,
         tx_out_index: usize,
         address: String,
         amount: u32,
+        is_coinbase: bool,
+        block_index: u32,
     ) -> Self {
         UTxO {
             tx_out_id,
             tx_out_index,
             address,
             amount,
+            is_coinbase,
+            block_index,
         }
     }
+
+    // Whether the output may be spent by a transaction in the block at `block_index`
+    pub fn is_mature(&self, block_index: u32, coinbase_maturity: u32) -> bool {
+        !self.is_coinbase || block_index >= self.block_index.saturating_add(coinbase_maturity)
+    }
 }
@@ -0,0 +1,70 @@
+use std::collections::HashMap;
+use crate::{
+    block::Block,
+    transaction::{
+        Transaction,
+        utxo::UTxO,
+    },
+};
+
+// Unspent outputs keyed by their outpoint, the id of the transaction that created them and
+// their index in its outputs
+#[derive(Clone, Default)]
+pub struct UtxoSet(HashMap<(String, usize), UTxO>);
+
+impl UtxoSet {
+    pub fn new(utxo_list: Vec<UTxO>) -> Self {
+        UtxoSet(
+            utxo_list.into_iter()
+                .map(|utxo| ((utxo.tx_out_id(), utxo.tx_out_index()), utxo))
+                .collect()
+        )
+    }
+
+    pub fn from_blocks(blocks: &[Block]) -> Self {
+        let mut utxo_set = UtxoSet::default();
+        for block in blocks.iter() {
+            utxo_set.apply_transactions(block.index(), &block.data());
+        }
+        utxo_set
+    }
+
+    pub fn utxo_list(&self) -> Vec<UTxO> {
+        self.0.values().cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn find(&self, tx_out_id: &str, tx_out_index: usize) -> Option<&UTxO> {
+        self.0.get(&(tx_out_id.to_string(), tx_out_index))
+    }
+
+    pub fn contains(&self, tx_out_id: &str, tx_out_index: usize) -> bool {
+        self.find(tx_out_id, tx_out_index).is_some()
+    }
+
+    pub fn find_by_address(&self, address: String) -> Vec<UTxO> {
+        self.0.values()
+            .filter(|utxo| utxo.address() == address)
+            .cloned()
+            .collect()
+    }
+
+    // Applies the transactions of the block at `block_index`, the first being its coinbase
+    pub fn apply_transactions(&mut self, block_index: u32, transactions: &[Transaction]) {
+        for (index, transaction) in transactions.iter().enumerate() {
+            for tx_in in transaction.tx_in_list().iter() {
+                self.0.remove(&(tx_in.tx_out_id(), tx_in.tx_out_index()));
+            }
+            for utxo in transaction.new_utxo(index == 0, block_index) {
+                self.0.insert((utxo.tx_out_id(), utxo.tx_out_index()), utxo);
+            }
+        }
+    }
+}
@@ -0,0 +1,179 @@
+use serde::Serialize;
+use crate::transaction::{
+    Transaction,
+    utxo_set::UtxoSet,
+};
+
+// Block assembly stops adding pooled transactions once their total size would exceed this
+pub const MAX_BLOCK_TRANSACTIONS_SIZE: usize = 1024 * 1024;
+
+#[derive(Clone, Default, Serialize)]
+pub struct TransactionPool(Vec<Transaction>);
+
+impl TransactionPool {
+    pub fn new() -> Self {
+        TransactionPool(vec![])
+    }
+
+    pub fn transactions(&self) -> Vec<Transaction> {
+        self.0.clone()
+    }
+
+    // Picks the transactions for a new block, highest fee per byte first, up to
+    // MAX_BLOCK_TRANSACTIONS_SIZE
+    pub fn select_transactions(&self, utxo_set: &UtxoSet) -> Vec<Transaction> {
+        let mut candidates = self.0.iter()
+            .map(|transaction| (transaction.fee(utxo_set) as u128, transaction.size().max(1) as u128, transaction))
+            .collect::<Vec<(u128, u128, &Transaction)>>();
+        // Compares fee_a / size_a with fee_b / size_b without dividing
+        candidates.sort_by(|(fee_a, size_a, _), (fee_b, size_b, _)| (fee_b * size_a).cmp(&(fee_a * size_b)));
+
+        let mut total_size = 0;
+        let mut transactions = vec![];
+        for (_, size, transaction) in candidates {
+            if total_size + size as usize > MAX_BLOCK_TRANSACTIONS_SIZE {
+                continue
+            }
+            total_size += size as usize;
+            transactions.push(transaction.clone());
+        }
+        transactions
+    }
+
+    // `next_block_index` is the index of the block the transaction would go in
+    pub fn add_transaction(
+        &mut self,
+        transaction: Transaction,
+        utxo_set: &UtxoSet,
+        next_block_index: u32,
+        coinbase_maturity: u32,
+    ) -> Result<(), String> {
+        Self::validate_transaction(&transaction, utxo_set, next_block_index, coinbase_maturity)?;
+
+        if self.is_double_spend(&transaction) {
+            return Err("TxIn is already spent by a transaction in the pool".to_string())
+        }
+
+        self.0.push(transaction);
+        Ok(())
+    }
+
+    // Drops every pooled transaction that refers to an output which is no longer unspent,
+    // e.g. because it was included in a new block, or to a coinbase output that is no longer
+    // mature after a chain replacement.
+    pub fn update(&mut self, utxo_set: &UtxoSet, next_block_index: u32, coinbase_maturity: u32) {
+        self.0.retain(|transaction| {
+            transaction.tx_in_list().iter().all(|tx_in| utxo_set.contains(&tx_in.tx_out_id(), tx_in.tx_out_index()))
+            && transaction.check_maturity(utxo_set, next_block_index, coinbase_maturity).is_ok()
+        });
+    }
+
+    fn validate_transaction(
+        transaction: &Transaction,
+        utxo_set: &UtxoSet,
+        next_block_index: u32,
+        coinbase_maturity: u32,
+    ) -> Result<(), String> {
+        transaction.is_valid()?;
+
+        for tx_in in transaction.tx_in_list().iter() {
+            tx_in.is_valid(transaction, utxo_set)?;
+        }
+
+        transaction.check_amount_between_in_and_out(utxo_set)?;
+        transaction.check_maturity(utxo_set, next_block_index, coinbase_maturity)?;
+        Ok(())
+    }
+
+    fn is_double_spend(&self, transaction: &Transaction) -> bool {
+        self.0.iter().any(|pooled_transaction| {
+            pooled_transaction.tx_in_list().iter().any(|pooled_tx_in| {
+                transaction.tx_in_list().iter().any(|tx_in| {
+                    pooled_tx_in.tx_out_id() == tx_in.tx_out_id()
+                    && pooled_tx_in.tx_out_index() == tx_in.tx_out_index()
+                })
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        mylib::ecdsa::{generate_private_key, public_key_from_private_key},
+        transaction::{
+            subsidy::SubsidySchedule,
+            utxo::COINBASE_MATURITY,
+        },
+    };
+
+    // An output paying `address`, created by a transaction that is not a coinbase
+    fn spendable_utxo_set(address: String) -> UtxoSet {
+        UtxoSet::new(Transaction::coinbase(address, 1, 0, &SubsidySchedule::default()).new_utxo(false, 1))
+    }
+
+    #[test]
+    fn rejects_a_double_spend_of_a_pooled_input() {
+        let private_key = generate_private_key();
+        let address = public_key_from_private_key(private_key.clone()).unwrap();
+        let other_address = public_key_from_private_key(generate_private_key()).unwrap();
+        let utxo_set = spendable_utxo_set(address.clone());
+        let spend = Transaction::signed(&utxo_set.utxo_list(), private_key.clone(), address);
+        let double_spend = Transaction::signed(&utxo_set.utxo_list(), private_key, other_address);
+        let mut transaction_pool = TransactionPool::new();
+
+        assert_eq!(transaction_pool.add_transaction(spend.clone(), &utxo_set, 2, COINBASE_MATURITY), Ok(()));
+        assert_eq!(
+            transaction_pool.add_transaction(double_spend, &utxo_set, 2, COINBASE_MATURITY),
+            Err("TxIn is already spent by a transaction in the pool".to_string()),
+        );
+        assert_eq!(
+            transaction_pool.add_transaction(spend, &utxo_set, 2, COINBASE_MATURITY),
+            Err("TxIn is already spent by a transaction in the pool".to_string()),
+        );
+        assert_eq!(transaction_pool.transactions().len(), 1);
+    }
+
+    #[test]
+    fn update_drops_transactions_whose_inputs_were_spent() {
+        let private_key = generate_private_key();
+        let address = public_key_from_private_key(private_key.clone()).unwrap();
+        let utxo_set = spendable_utxo_set(address.clone());
+        let spend = Transaction::signed(&utxo_set.utxo_list(), private_key, address);
+        let mut transaction_pool = TransactionPool::new();
+        transaction_pool.add_transaction(spend.clone(), &utxo_set, 2, COINBASE_MATURITY).unwrap();
+
+        transaction_pool.update(&utxo_set, 3, COINBASE_MATURITY);
+        assert_eq!(transaction_pool.transactions().len(), 1);
+
+        // The input was spent by a block, so it is no longer unspent
+        transaction_pool.update(&UtxoSet::default(), 3, COINBASE_MATURITY);
+        assert!(transaction_pool.transactions().is_empty());
+    }
+
+    #[test]
+    fn admits_coinbase_spends_once_mature() {
+        let private_key = generate_private_key();
+        let address = public_key_from_private_key(private_key.clone()).unwrap();
+        let mined_at = 5;
+        let coinbase = Transaction::coinbase(address.clone(), mined_at, 0, &SubsidySchedule::default());
+        let utxo_set = UtxoSet::new(coinbase.new_utxo(true, mined_at));
+        let spend = Transaction::signed(&utxo_set.utxo_list(), private_key, address);
+        let maturity = 3;
+        let mut transaction_pool = TransactionPool::new();
+
+        assert_eq!(
+            transaction_pool.add_transaction(spend.clone(), &utxo_set, mined_at + maturity - 1, maturity),
+            Err("TxIn spends an immature coinbase output".to_string()),
+        );
+        assert!(transaction_pool.transactions().is_empty());
+
+        assert_eq!(transaction_pool.add_transaction(spend.clone(), &utxo_set, mined_at + maturity, maturity), Ok(()));
+        assert_eq!(transaction_pool.transactions().len(), 1);
+
+        // Pooled spends stay only while mature under the configured maturity
+        transaction_pool.update(&utxo_set, mined_at + maturity, COINBASE_MATURITY);
+        assert!(transaction_pool.transactions().is_empty());
+    }
+}
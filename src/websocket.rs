@@ -1,33 +1,107 @@
 use std::{
-    net::{TcpListener, TcpStream},
-    io::{Read, Write},
     collections::HashMap,
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    time::Duration,
 };
 
-use base64::{engine::general_purpose, Engine as _};
-use sha1::{Digest, Sha1};
+use tokio::net::TcpListener;
 use crate::websocket::{
+    client::WebSocketClient,
+    close_code::CloseCode,
+    connection::{Connection, WebSocketSender},
+    decoder::DEFAULT_MAX_PAYLOAD_SIZE,
     frame::Frame,
+    handle::{ConnectionId, WebSocketHandle},
     opcode::Opcode,
 };
 
+pub mod client;
+pub mod close_code;
+pub mod connection;
+pub mod decoder;
+pub mod deflate;
 pub mod opcode;
 pub mod frame;
+pub mod handle;
+pub mod handshake;
 pub const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
 
-enum ConnectionPhase {
-    Handshake,
-    Open,
+pub struct WebSocketContext {
+    pub connection_id: ConnectionId,
+    pub peer_addr: String,
+    pub sender: WebSocketSender,
+    // Reaches the other connections of the server
+    pub handle: WebSocketHandle,
+    pub frame: Frame,
+    // The subprotocol agreed on in the opening handshake
+    pub protocol: Option<String>,
 }
 
-pub struct WebSocketContext<'a> {
-    pub stream: &'a mut TcpStream,
-    pub frame: Frame,
+// What the connection should do once an operation has handled a message
+pub enum OperationResult {
+    Continue,
+    Reply(Frame),
+    Close(CloseCode, String),
+}
+
+pub type Operation = Arc<dyn Fn(WebSocketContext) -> Pin<Box<dyn Future<Output = OperationResult> + Send>> + Send + Sync>;
+
+// Called with the connection id and peer address once the opening handshake is done, and
+// again once the connection has ended, whatever the reason
+pub type ConnectHandler = Arc<dyn Fn(ConnectionId, String) + Send + Sync>;
+pub type DisconnectHandler = Arc<dyn Fn(ConnectionId, String) + Send + Sync>;
+
+#[derive(Clone)]
+pub struct WebSocketConfig {
+    // Outgoing data frames with a larger payload are split into continuation frames
+    pub max_frame_size: Option<usize>,
+    // Pings are sent on this interval, and a peer that misses `max_missed_pongs` Pongs
+    // in a row is disconnected
+    pub ping_interval: Option<Duration>,
+    pub max_missed_pongs: u32,
+    // A peer that has not finished the opening handshake within this time is dropped
+    pub handshake_timeout: Duration,
+    pub on_connect: Option<ConnectHandler>,
+    pub on_disconnect: Option<DisconnectHandler>,
+    // Paths the opening handshake may request. Any path is accepted when empty.
+    pub routes: Vec<String>,
+    // Subprotocols we speak, in order of preference when dialing
+    pub protocols: Vec<String>,
+    pub permessage_deflate: bool,
+    // Limits on what a peer may send us. A peer going over them is closed with 1009.
+    pub max_payload_size: usize,
+    pub max_message_size: usize,
+    // Messages waiting to be written to a peer. A peer that lets the queue fill up is
+    // disconnected with 1008 rather than silently missing messages.
+    pub max_queued_messages: usize,
+}
+
+impl Default for WebSocketConfig {
+    fn default() -> Self {
+        WebSocketConfig {
+            max_frame_size: None,
+            ping_interval: None,
+            max_missed_pongs: 3,
+            handshake_timeout: Duration::from_secs(10),
+            on_connect: None,
+            on_disconnect: None,
+            routes: vec![],
+            protocols: vec![],
+            permessage_deflate: false,
+            max_payload_size: DEFAULT_MAX_PAYLOAD_SIZE,
+            max_message_size: 4 * DEFAULT_MAX_PAYLOAD_SIZE,
+            max_queued_messages: 1024,
+        }
+    }
 }
 
 pub struct WebSocketBuilder {
     endpoint: Option<String>,
-    operations: HashMap<Opcode, fn(WebSocketContext)>,
+    operations: HashMap<Opcode, Operation>,
+    config: WebSocketConfig,
+    handle: WebSocketHandle,
 }
 
 impl WebSocketBuilder {
@@ -36,8 +110,84 @@ impl WebSocketBuilder {
         self
     }
 
-    pub fn add_operation(mut self, opcode: Opcode, func: fn(WebSocketContext)) -> Self {
-        self.operations.insert(opcode, func);
+    // Servers built with the same handle share their connections
+    pub fn handle(mut self, handle: WebSocketHandle) -> Self {
+        self.handle = handle;
+        self
+    }
+
+    pub fn route(mut self, path: String) -> Self {
+        self.config.routes.push(path);
+        self
+    }
+
+    pub fn protocol(mut self, protocol: String) -> Self {
+        self.config.protocols.push(protocol);
+        self
+    }
+
+    pub fn permessage_deflate(mut self) -> Self {
+        self.config.permessage_deflate = true;
+        self
+    }
+
+    pub fn max_payload_size(mut self, max_payload_size: usize) -> Self {
+        self.config.max_payload_size = max_payload_size;
+        self
+    }
+
+    pub fn max_message_size(mut self, max_message_size: usize) -> Self {
+        self.config.max_message_size = max_message_size;
+        self
+    }
+
+    pub fn max_queued_messages(mut self, max_queued_messages: usize) -> Self {
+        self.config.max_queued_messages = max_queued_messages;
+        self
+    }
+
+    pub fn max_frame_size(mut self, max_frame_size: usize) -> Self {
+        self.config.max_frame_size = Some(max_frame_size);
+        self
+    }
+
+    pub fn ping_interval(mut self, ping_interval: Duration) -> Self {
+        self.config.ping_interval = Some(ping_interval);
+        self
+    }
+
+    pub fn max_missed_pongs(mut self, max_missed_pongs: u32) -> Self {
+        self.config.max_missed_pongs = max_missed_pongs;
+        self
+    }
+
+    pub fn handshake_timeout(mut self, handshake_timeout: Duration) -> Self {
+        self.config.handshake_timeout = handshake_timeout;
+        self
+    }
+
+    pub fn on_connect<F>(mut self, func: F) -> Self
+    where
+        F: Fn(ConnectionId, String) + Send + Sync + 'static,
+    {
+        self.config.on_connect = Some(Arc::new(func));
+        self
+    }
+
+    pub fn on_disconnect<F>(mut self, func: F) -> Self
+    where
+        F: Fn(ConnectionId, String) + Send + Sync + 'static,
+    {
+        self.config.on_disconnect = Some(Arc::new(func));
+        self
+    }
+
+    pub fn add_operation<F, Fut>(mut self, opcode: Opcode, func: F) -> Self
+    where
+        F: Fn(WebSocketContext) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = OperationResult> + Send + 'static,
+    {
+        self.operations.insert(opcode, Arc::new(move |context| Box::pin(func(context))));
         self
     }
 
@@ -45,27 +195,32 @@ impl WebSocketBuilder {
         WebSocket::new(
             self.endpoint.clone().unwrap(),
             self.operations.clone(),
-            ConnectionPhase::Handshake,
+            self.config.clone(),
+            self.handle.clone(),
         )
     }
 }
 
+#[derive(Clone)]
 pub struct WebSocket {
     endpoint: String,
-    operations: HashMap<Opcode, fn(WebSocketContext)>,
-    connection_phase: ConnectionPhase,
+    operations: HashMap<Opcode, Operation>,
+    config: WebSocketConfig,
+    handle: WebSocketHandle,
 }
 
 impl WebSocket {
     fn new(
         endpoint: String,
-        operations: HashMap<Opcode, fn(WebSocketContext)>,
-        connection_phase: ConnectionPhase
+        operations: HashMap<Opcode, Operation>,
+        config: WebSocketConfig,
+        handle: WebSocketHandle,
     ) -> Self {
         WebSocket {
             endpoint,
             operations,
-            connection_phase,
+            config,
+            handle,
         }
     }
 
@@ -73,80 +228,42 @@ impl WebSocket {
         WebSocketBuilder {
             endpoint: None,
             operations: HashMap::new(),
+            config: WebSocketConfig::default(),
+            handle: WebSocketHandle::new(),
         }
     }
 
-    pub fn listen(&mut self) {
-        let listener = TcpListener::bind(self.endpoint.clone()).unwrap();
-        let mut buffer = [0; 4096];
-
-        for stream in listener.incoming() {
-            let mut stream = stream.unwrap();
-            loop {
-                println!("listening...");
-                stream.read(&mut buffer).unwrap();
-                match self.connection_phase {
-                    ConnectionPhase::Handshake => self.opening_handshake(&mut stream, buffer),
-                    ConnectionPhase::Open => self.websocket_connection(&mut stream, buffer),
-                };
-            }
-        }
+    pub fn handle(&self) -> WebSocketHandle {
+        self.handle.clone()
     }
 
-    fn opening_handshake(&mut self, stream: &mut TcpStream, buffer: [u8; 4096]) {
-        let mut request_headers = HashMap::new();
-
-        let request_text = String::from_utf8_lossy(&buffer[..]);
-        for (i, line) in request_text.lines().enumerate() {
-            if line == "" {
-                break;
-            }
+    pub async fn listen(&self) {
+        let listener = TcpListener::bind(self.endpoint.clone()).await.unwrap();
 
-            if i == 0 {
-                let values = line.split(" ").map(|s| s.trim()).collect::<Vec<&str>>();
-                request_headers.insert("method".to_string(), values[0].to_string());
-                request_headers.insert("uri".to_string(), values[1].to_string());
-                request_headers.insert("protocol_version".to_string(), values[2].to_string());
-                continue;
-            }
-
-            let values = line.split(":").map(|s| s.trim()).collect::<Vec<&str>>();
-            request_headers.insert(values[0].to_lowercase().to_string(), values[1].to_string());
+        loop {
+            let stream = match listener.accept().await {
+                Ok((v, _)) => v,
+                Err(_) => continue,
+            };
+            let connection = Connection::server(stream, self.operations.clone(), self.config.clone(), self.handle.clone());
+            tokio::spawn(connection.run());
         }
+    }
 
-        let mut hasher = Sha1::new();
-        hasher.update(format!("{}{}", request_headers.get("sec-websocket-key").unwrap(), WEBSOCKET_GUID));
-        let sec_websocket_accept = general_purpose::STANDARD.encode(hasher.finalize());
-
-        let response = format!("{}\r\n{}\r\n{}\r\n{}{}\r\n\r\n",
-            "HTTP/1.1 101 OK",
-            "Upgrade: websocket",
-            "Connection: Upgrade",
-            "Sec-WebSocket-Accept: ", sec_websocket_accept,
+    // Dials another server and handles the frames it sends with this server's operations
+    pub async fn connect(&self, url: String) -> Result<ConnectionId, String> {
+        let client = WebSocketClient::connect_with(url, &self.config.protocols, self.config.permessage_deflate).await?;
+        let negotiation = client.negotiation();
+        let connection = Connection::client(
+            client.into_stream(),
+            self.operations.clone(),
+            self.config.clone(),
+            negotiation,
+            self.handle.clone(),
         );
-        stream.write(response.as_bytes()).unwrap();
-        stream.flush().unwrap();
-
-        self.connection_phase = ConnectionPhase::Open;
-    }
-
-    fn websocket_connection(&mut self, stream: &mut TcpStream, buffer: [u8; 4096]) {
-        let frame = Frame::from(&buffer[..]);
-        match frame.opcode() {
-            Opcode::Text => {
-                if let Some(func) = self.operations.get(&Opcode::Text) {
-                    func(WebSocketContext { stream, frame });
-                }
-            },
-            Opcode::Close => {
-                let response = Frame::new(Opcode::Close, None);
-                stream.write(&response.to_bytes()).unwrap();
-                stream.flush().unwrap();
-
-                println!("closed");
-                self.connection_phase = ConnectionPhase::Handshake;
-            }
-            _ => {},
-        };
+        let connection_id = connection.id();
+        tokio::spawn(connection.run());
+
+        Ok(connection_id)
     }
 }
@@ -0,0 +1,192 @@
+use base64::{engine::general_purpose, Engine as _};
+use rand_core::{OsRng, RngCore};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+use crate::websocket::{
+    close_code::CloseCode,
+    decoder::FrameDecoder,
+    deflate::{self, Decompressor},
+    connection::Connection,
+    frame::Frame,
+    handshake::{MAX_HANDSHAKE_SIZE, Negotiation, accept_key, has_token, parse_headers},
+    opcode::Opcode,
+};
+
+pub struct WebSocketClient {
+    stream: TcpStream,
+    decoder: FrameDecoder,
+    negotiation: Negotiation,
+    decompressor: Option<Decompressor>,
+    // The fragments of a message received so far
+    message: Option<(Opcode, bool, Vec<u8>)>,
+}
+
+impl WebSocketClient {
+    // Connects to `url` (`ws://host:port/path`, or just `host:port`) and performs the
+    // client side of the opening handshake
+    pub async fn connect(url: String) -> Result<Self, String> {
+        Self::connect_with(url, &[], false).await
+    }
+
+    // Also asks for one of `protocols` and, if `permessage_deflate` is set, for compression
+    pub async fn connect_with(url: String, protocols: &[String], permessage_deflate: bool) -> Result<Self, String> {
+        let address = url.strip_prefix("ws://").unwrap_or(&url);
+        let (host, path) = match address.find('/') {
+            Some(i) => (&address[..i], &address[i..]),
+            None => (address, "/"),
+        };
+
+        let mut stream = TcpStream::connect(host).await.map_err(|e| e.to_string())?;
+
+        let mut key = [0; 16];
+        OsRng.fill_bytes(&mut key);
+        let sec_websocket_key = general_purpose::STANDARD.encode(key);
+        let mut request = format!("{}{}{}\r\n{}{}\r\n{}\r\n{}\r\n{}{}\r\n{}\r\n",
+            "GET ", path, " HTTP/1.1",
+            "Host: ", host,
+            "Upgrade: websocket",
+            "Connection: Upgrade",
+            "Sec-WebSocket-Key: ", sec_websocket_key,
+            "Sec-WebSocket-Version: 13",
+        );
+        if !protocols.is_empty() {
+            request.push_str(&format!("Sec-WebSocket-Protocol: {}\r\n", protocols.join(", ")));
+        }
+        if permessage_deflate {
+            request.push_str(&format!("Sec-WebSocket-Extensions: {}\r\n", deflate::offer()));
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes()).await.map_err(|e| e.to_string())?;
+        stream.flush().await.map_err(|e| e.to_string())?;
+
+        let response = Self::read_handshake_response(&mut stream).await?;
+        let negotiation = Self::validate_handshake_response(response, sec_websocket_key, protocols, permessage_deflate)?;
+
+        Ok(WebSocketClient {
+            stream,
+            decoder: FrameDecoder::new(),
+            decompressor: negotiation.deflate.then(|| Decompressor::new(negotiation.peer_context_takeover)),
+            negotiation,
+            message: None,
+        })
+    }
+
+    pub fn negotiation(&self) -> Negotiation {
+        self.negotiation.clone()
+    }
+
+    pub fn into_stream(self) -> TcpStream {
+        self.stream
+    }
+
+    pub fn peer_addr(&self) -> Result<String, String> {
+        self.stream.peer_addr().map(|v| v.to_string()).map_err(|e| e.to_string())
+    }
+
+    pub async fn send(&mut self, frame: Frame) -> Result<(), String> {
+        let frame = match frame.opcode() {
+            Opcode::Text | Opcode::Binary if self.negotiation.deflate && frame.fin() => {
+                Frame::new(frame.opcode(), Some(deflate::compress(&frame.payload_data()))).with_rsv1(true)
+            },
+            _ => frame,
+        };
+        let mut masking_key = [0; 4];
+        OsRng.fill_bytes(&mut masking_key);
+
+        self.stream.write_all(&frame.masked(masking_key).to_bytes()).await.map_err(|e| e.to_string())?;
+        self.stream.flush().await.map_err(|e| e.to_string())
+    }
+
+    pub async fn send_text(&mut self, text: String) -> Result<(), String> {
+        self.send(Frame::new(Opcode::Text, Some(text.into_bytes()))).await
+    }
+
+    pub async fn close(&mut self, code: CloseCode, reason: &str) -> Result<(), String> {
+        self.send(Frame::close(code, reason)).await
+    }
+
+    // Waits until a whole message or control frame has been received, reassembling
+    // fragmented messages and inflating compressed ones
+    pub async fn receive(&mut self) -> Result<Frame, String> {
+        let mut buffer = [0; 4096];
+        loop {
+            let frame = Connection::next_frame(
+                &mut self.decoder,
+                &mut self.message,
+                &mut self.decompressor,
+                true,
+                usize::MAX,
+            ).map_err(|e| e.to_string())?;
+            if let Some(frame) = frame {
+                return Ok(frame)
+            }
+            let size = self.stream.read(&mut buffer).await.map_err(|e| e.to_string())?;
+            if size == 0 {
+                return Err("Connection closed".to_string())
+            }
+            self.decoder.extend(&buffer[..size]);
+        }
+    }
+
+    // Reads byte by byte so that no frame following the response is consumed
+    async fn read_handshake_response(stream: &mut TcpStream) -> Result<String, String> {
+        let mut response = Vec::new();
+        let mut byte = [0; 1];
+        while !response.ends_with(b"\r\n\r\n") {
+            if stream.read(&mut byte).await.map_err(|e| e.to_string())? == 0 {
+                return Err("Connection closed during handshake".to_string())
+            }
+            response.push(byte[0]);
+            if response.len() > MAX_HANDSHAKE_SIZE {
+                return Err("Handshake response is too large".to_string())
+            }
+        }
+        Ok(String::from_utf8_lossy(&response).to_string())
+    }
+
+    fn validate_handshake_response(
+        response: String,
+        sec_websocket_key: String,
+        protocols: &[String],
+        permessage_deflate: bool,
+    ) -> Result<Negotiation, String> {
+        let mut lines = response.split("\r\n");
+        let status_line = lines.next().unwrap_or("");
+        if status_line.split(' ').nth(1) != Some("101") {
+            return Err(format!("Unexpected handshake response: {}", status_line))
+        }
+
+        let response_headers = parse_headers(lines)
+            .ok_or("Malformed header in handshake response".to_string())?;
+        if !has_token(&response_headers, "upgrade", "websocket") {
+            return Err("Invalid Upgrade header in handshake response".to_string())
+        }
+        if !has_token(&response_headers, "connection", "upgrade") {
+            return Err("Invalid Connection header in handshake response".to_string())
+        }
+
+        if response_headers.get("sec-websocket-accept") != Some(&accept_key(&sec_websocket_key)) {
+            return Err("Invalid Sec-WebSocket-Accept in handshake response".to_string())
+        }
+
+        let protocol = response_headers.get("sec-websocket-protocol").cloned();
+        if protocol.as_ref().is_some_and(|v| !protocols.contains(v)) {
+            return Err("Unexpected Sec-WebSocket-Protocol in handshake response".to_string())
+        }
+        let context_takeover = match response_headers.get("sec-websocket-extensions") {
+            Some(extensions) => deflate::parse_answer(extensions)?,
+            None => None,
+        };
+        if context_takeover.is_some() && !permessage_deflate {
+            return Err("Unexpected Sec-WebSocket-Extensions in handshake response".to_string())
+        }
+
+        Ok(Negotiation {
+            protocol,
+            deflate: context_takeover.is_some(),
+            peer_context_takeover: context_takeover.unwrap_or(false),
+        })
+    }
+}
@@ -0,0 +1,58 @@
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum CloseCode {
+    Normal,             // 1000
+    GoingAway,          // 1001
+    ProtocolError,      // 1002
+    UnsupportedData,    // 1003
+    InvalidPayload,     // 1007
+    PolicyViolation,    // 1008
+    MessageTooBig,      // 1009
+    MandatoryExtension, // 1010
+    InternalError,      // 1011
+    Other(u16),
+}
+
+impl CloseCode {
+    // Whether the code may appear in a close frame (RFC 6455 section 7.4). 1005, 1006 and
+    // 1015 are reserved for reporting a close that had no status code.
+    pub fn is_valid(&self) -> bool {
+        match self {
+            CloseCode::Other(code) => matches!(code, 1012..=1014 | 3000..=4999),
+            _ => true,
+        }
+    }
+}
+
+impl From<u16> for CloseCode {
+    fn from(code: u16) -> CloseCode {
+        match code {
+            1000 => CloseCode::Normal,
+            1001 => CloseCode::GoingAway,
+            1002 => CloseCode::ProtocolError,
+            1003 => CloseCode::UnsupportedData,
+            1007 => CloseCode::InvalidPayload,
+            1008 => CloseCode::PolicyViolation,
+            1009 => CloseCode::MessageTooBig,
+            1010 => CloseCode::MandatoryExtension,
+            1011 => CloseCode::InternalError,
+            code => CloseCode::Other(code),
+        }
+    }
+}
+
+impl From<CloseCode> for u16 {
+    fn from(code: CloseCode) -> u16 {
+        match code {
+            CloseCode::Normal             => 1000,
+            CloseCode::GoingAway          => 1001,
+            CloseCode::ProtocolError      => 1002,
+            CloseCode::UnsupportedData    => 1003,
+            CloseCode::InvalidPayload     => 1007,
+            CloseCode::PolicyViolation    => 1008,
+            CloseCode::MessageTooBig      => 1009,
+            CloseCode::MandatoryExtension => 1010,
+            CloseCode::InternalError      => 1011,
+            CloseCode::Other(code)        => code,
+        }
+    }
+}
@@ -0,0 +1,643 @@
+use std::{
+    collections::HashMap,
+    future,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
+
+use rand_core::{OsRng, RngCore};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{
+        TcpStream,
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+    },
+    sync::{
+        mpsc::{self, Receiver, Sender, error::TrySendError},
+        oneshot,
+        watch,
+    },
+    time::{self, Instant, Interval},
+};
+use crate::websocket::{
+    Operation,
+    OperationResult,
+    WebSocketConfig,
+    WebSocketContext,
+    close_code::CloseCode,
+    decoder::FrameDecoder,
+    deflate::{self, Decompressor},
+    frame::{Frame, FrameError},
+    handle::{ConnectionId, WebSocketHandle},
+    handshake::{HandshakeRequest, Negotiation},
+    opcode::Opcode,
+};
+
+// How long to wait for the peer to answer a close frame before dropping the connection
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+// RFC 6455 caps the payload of control frames
+const MAX_CONTROL_PAYLOAD_SIZE: usize = 125;
+
+enum ConnectionPhase {
+    Handshake,
+    Open,
+    Closed,
+}
+
+// Queues frames to be written to one connection. Frames sent from the client side of a
+// connection are masked as RFC 6455 requires, and messages larger than the configured
+// max frame size are fragmented. Nothing can be sent once a close frame has been sent.
+// Sending fails while the queue is full, and the connection is then dropped with 1008,
+// since its peer has fallen too far behind to get every frame.
+#[derive(Clone)]
+pub struct WebSocketSender {
+    sender: Sender<Vec<u8>>,
+    is_client: bool,
+    max_frame_size: Option<usize>,
+    deflate: bool,
+    closing: Arc<watch::Sender<bool>>,
+    overflowed: Arc<AtomicBool>,
+}
+
+impl WebSocketSender {
+    pub fn send(&self, frame: Frame) -> Result<(), String> {
+        if self.is_closing() {
+            return Err("Connection is closed".to_string())
+        }
+        self.send_frame(frame)
+    }
+
+    pub fn send_text(&self, text: String) -> Result<(), String> {
+        self.send(Frame::new(Opcode::Text, Some(text.into_bytes())))
+    }
+
+    // Starts the closing handshake. The connection is dropped once the peer answers, or
+    // when it has not answered in time.
+    pub fn close(&self, code: CloseCode, reason: &str) -> Result<(), String> {
+        self.close_with(Frame::close(code, reason))
+    }
+
+    pub fn is_closing(&self) -> bool {
+        *self.closing.borrow()
+    }
+
+    fn close_with(&self, frame: Frame) -> Result<(), String> {
+        if self.closing.send_replace(true) {
+            return Err("Connection is closed".to_string())
+        }
+        self.send_frame(frame)
+    }
+
+    fn send_frame(&self, frame: Frame) -> Result<(), String> {
+        // Queued at once so that fragments from different senders are not interleaved
+        self.send_bytes(self.encode(frame))
+    }
+
+    fn encode(&self, frame: Frame) -> Vec<u8> {
+        let frame = match frame.opcode() {
+            Opcode::Text | Opcode::Binary if self.deflate && frame.fin() => {
+                Frame::new(frame.opcode(), Some(deflate::compress(&frame.payload_data()))).with_rsv1(true)
+            },
+            _ => frame,
+        };
+        let frames = match self.max_frame_size {
+            Some(max_frame_size) => frame.fragment(max_frame_size),
+            None => vec![frame],
+        };
+
+        let mut bytes = vec![];
+        for frame in frames.into_iter() {
+            let frame = if self.is_client {
+                let mut masking_key = [0; 4];
+                OsRng.fill_bytes(&mut masking_key);
+                frame.masked(masking_key)
+            } else {
+                frame
+            };
+            bytes.extend(frame.to_bytes());
+        }
+        bytes
+    }
+
+    fn send_bytes(&self, bytes: Vec<u8>) -> Result<(), String> {
+        self.sender.try_send(bytes).map_err(|e| match e {
+            TrySendError::Full(_) => {
+                self.overflowed.store(true, Ordering::SeqCst);
+                self.closing.send_replace(true);
+                "Outgoing queue is full".to_string()
+            },
+            TrySendError::Closed(_) => "Connection is closed".to_string(),
+        })
+    }
+}
+
+// Each connection owns its phase and runs as its own task; a second task writes the
+// frames queued through its WebSocketSender.
+pub struct Connection {
+    id: ConnectionId,
+    reader: OwnedReadHalf,
+    writer: OwnedWriteHalf,
+    peer_addr: String,
+    connection_phase: ConnectionPhase,
+    operations: HashMap<Opcode, Operation>,
+    config: WebSocketConfig,
+    negotiation: Negotiation,
+    handle: WebSocketHandle,
+    sender: WebSocketSender,
+    receiver: Receiver<Vec<u8>>,
+}
+
+impl Connection {
+    pub fn server(
+        stream: TcpStream,
+        operations: HashMap<Opcode, Operation>,
+        config: WebSocketConfig,
+        handle: WebSocketHandle,
+    ) -> Self {
+        Self::new(stream, operations, config, Negotiation::default(), handle, ConnectionPhase::Handshake, false)
+    }
+
+    // For a stream whose opening handshake has already been done by a client
+    pub fn client(
+        stream: TcpStream,
+        operations: HashMap<Opcode, Operation>,
+        config: WebSocketConfig,
+        negotiation: Negotiation,
+        handle: WebSocketHandle,
+    ) -> Self {
+        Self::new(stream, operations, config, negotiation, handle, ConnectionPhase::Open, true)
+    }
+
+    fn new(
+        stream: TcpStream,
+        operations: HashMap<Opcode, Operation>,
+        config: WebSocketConfig,
+        negotiation: Negotiation,
+        handle: WebSocketHandle,
+        connection_phase: ConnectionPhase,
+        is_client: bool,
+    ) -> Self {
+        let peer_addr = stream.peer_addr().map(|v| v.to_string()).unwrap_or_default();
+        let (reader, writer) = stream.into_split();
+        let (sender, receiver) = mpsc::channel(config.max_queued_messages.max(1));
+        let sender = WebSocketSender {
+            sender,
+            is_client,
+            max_frame_size: config.max_frame_size,
+            deflate: negotiation.deflate,
+            closing: Arc::new(watch::Sender::new(false)),
+            overflowed: Arc::new(AtomicBool::new(false)),
+        };
+        let id = handle.next_id();
+        // Registered right away, so that the connection can be reached as soon as the
+        // caller has its id
+        if let ConnectionPhase::Open = connection_phase {
+            handle.register(id, peer_addr.clone(), sender.clone());
+        }
+        Connection {
+            id,
+            reader,
+            writer,
+            peer_addr,
+            connection_phase,
+            operations,
+            sender,
+            config,
+            negotiation,
+            handle,
+            receiver,
+        }
+    }
+
+    pub fn id(&self) -> ConnectionId {
+        self.id
+    }
+
+    pub fn sender(&self) -> WebSocketSender {
+        self.sender.clone()
+    }
+
+    pub fn peer_addr(&self) -> String {
+        self.peer_addr.clone()
+    }
+
+    pub async fn run(self) {
+        let Connection {
+            id,
+            mut reader,
+            writer,
+            peer_addr,
+            mut connection_phase,
+            operations,
+            config,
+            mut negotiation,
+            handle,
+            mut sender,
+            receiver,
+        } = self;
+        let (shutdown, shutdown_receiver) = oneshot::channel();
+        let mut write_task = tokio::spawn(Self::write(writer, receiver, shutdown_receiver));
+
+        let mut ping_timer = config.ping_interval
+            .map(|ping_interval| time::interval_at(Instant::now() + ping_interval, ping_interval));
+        let mut missed_pongs = 0;
+        let mut closing = sender.closing.subscribe();
+        let mut close_deadline = None;
+        // Pings only start once the connection is open, so a peer that stalls during the
+        // opening handshake is dropped on this deadline instead
+        let handshake_deadline = Instant::now() + config.handshake_timeout;
+
+        let mut request = vec![];
+        let mut decoder = FrameDecoder::with_max_payload_size(config.max_payload_size);
+        let mut message = None;
+        let mut decompressor = negotiation.deflate.then(|| Decompressor::new(negotiation.peer_context_takeover));
+        let mut buffer = [0; 4096];
+
+        // Connections dialed as a client are open from the start
+        let mut is_open = matches!(connection_phase, ConnectionPhase::Open);
+        if let (true, Some(on_connect)) = (is_open, &config.on_connect) {
+            on_connect(id, peer_addr.clone());
+        }
+        'read: loop {
+            let size = tokio::select! {
+                result = reader.read(&mut buffer) => match result {
+                    Ok(0) | Err(_) => break,
+                    Ok(v) => v,
+                },
+                _ = Self::tick(&mut ping_timer) => {
+                    if let ConnectionPhase::Open = connection_phase {
+                        if missed_pongs >= config.max_missed_pongs {
+                            break
+                        }
+                        missed_pongs += 1;
+                        let _ = sender.send(Frame::new(Opcode::Ping, None));
+                    }
+                    continue
+                },
+                _ = closing.changed() => {
+                    if sender.overflowed.load(Ordering::SeqCst) {
+                        break
+                    }
+                    close_deadline = close_deadline.or(Some(Instant::now() + CLOSE_TIMEOUT));
+                    continue
+                },
+                _ = time::sleep_until(close_deadline.unwrap_or_else(Instant::now)), if close_deadline.is_some() => break,
+                _ = time::sleep_until(handshake_deadline), if !is_open => break,
+            };
+            if let ConnectionPhase::Handshake = connection_phase {
+                // The request may arrive over several reads, and frames sent right after
+                // it belong to the decoder
+                request.extend_from_slice(&buffer[..size]);
+                let request_len = match HandshakeRequest::len(&request) {
+                    Ok(Some(v)) => v,
+                    Ok(None) => continue,
+                    Err(e) => {
+                        let _ = sender.send_bytes(e.to_response().into_bytes());
+                        break
+                    },
+                };
+                match Self::opening_handshake(&sender, &config, &request[..request_len]) {
+                    Some(v) => {
+                        negotiation = v;
+                        sender.deflate = negotiation.deflate;
+                        decompressor = negotiation.deflate.then(|| Decompressor::new(negotiation.peer_context_takeover));
+                        connection_phase = ConnectionPhase::Open;
+                        handle.register(id, peer_addr.clone(), sender.clone());
+                        if let Some(on_connect) = &config.on_connect {
+                            on_connect(id, peer_addr.clone());
+                        }
+                        is_open = true;
+                    },
+                    None => break,
+                };
+                decoder.extend(&request[request_len..]);
+                request = vec![];
+            } else {
+                decoder.extend(&buffer[..size]);
+            }
+
+            while let ConnectionPhase::Open = connection_phase {
+                let frame = match Self::next_frame(
+                    &mut decoder,
+                    &mut message,
+                    &mut decompressor,
+                    sender.is_client,
+                    config.max_message_size,
+                ) {
+                    Ok(Some(v)) => v,
+                    Ok(None) => break,
+                    Err(e) => {
+                        // Protocol violations and peers going over our limits fail the
+                        // connection right away
+                        let _ = sender.close(e.close_code(), &e.to_string());
+                        break 'read
+                    },
+                };
+                if frame.opcode() == Opcode::Pong {
+                    missed_pongs = 0;
+                }
+                let context = WebSocketContext {
+                    connection_id: id,
+                    peer_addr: peer_addr.clone(),
+                    sender: sender.clone(),
+                    handle: handle.clone(),
+                    frame,
+                    protocol: negotiation.protocol.clone(),
+                };
+                connection_phase = Self::websocket_connection(&operations, context).await;
+            }
+            if let ConnectionPhase::Closed = connection_phase {
+                break
+            }
+        }
+
+        if is_open {
+            handle.unregister(id);
+        }
+        // A connection whose queue overflowed skips its backlog and only sends a close frame
+        let close_bytes = sender.overflowed.load(Ordering::SeqCst)
+            .then(|| sender.encode(Frame::close(CloseCode::PolicyViolation, "Outgoing queue is full")));
+        // A peer that stopped reading may keep the writer from ever finishing
+        let _ = shutdown.send(close_bytes);
+        if time::timeout(CLOSE_TIMEOUT, &mut write_task).await.is_err() {
+            write_task.abort();
+        }
+        if let (true, Some(on_disconnect)) = (is_open, config.on_disconnect) {
+            on_disconnect(id, peer_addr);
+        }
+    }
+
+    // Never completes when pings are disabled
+    async fn tick(ping_timer: &mut Option<Interval>) {
+        match ping_timer {
+            Some(ping_timer) => { ping_timer.tick().await; },
+            None => future::pending().await,
+        }
+    }
+
+    // Returns the next whole message or control frame decoded so far, inflating
+    // compressed messages
+    pub fn next_frame(
+        decoder: &mut FrameDecoder,
+        message: &mut Option<(Opcode, bool, Vec<u8>)>,
+        decompressor: &mut Option<Decompressor>,
+        is_client: bool,
+        max_message_size: usize,
+    ) -> Result<Option<Frame>, FrameError> {
+        while let Some(frame) = decoder.decode()? {
+            let frame = match Self::reassemble(message, frame, decompressor.is_some(), is_client, max_message_size)? {
+                Some(v) => v,
+                None => continue,
+            };
+            return match decompressor {
+                Some(decompressor) if frame.rsv1() => {
+                    let payload_data = decompressor.decompress(&frame.payload_data(), max_message_size)?;
+                    Ok(Some(Frame::new(frame.opcode(), Some(payload_data))))
+                },
+                _ => Ok(Some(frame)),
+            }
+        }
+        Ok(None)
+    }
+
+    // Collects the fragments of a message in `message` and returns the whole message once
+    // its final fragment arrives. Control frames pass straight through. RSV1 may only be
+    // set on the first frame of a message, and only when permessage-deflate is in use.
+    // Frames from a client must be masked and frames from a server must not be.
+    fn reassemble(
+        message: &mut Option<(Opcode, bool, Vec<u8>)>,
+        frame: Frame,
+        deflate: bool,
+        is_client: bool,
+        max_message_size: usize,
+    ) -> Result<Option<Frame>, FrameError> {
+        match (is_client, frame.mask()) {
+            (false, false) => return Err(FrameError::UnmaskedClientFrame),
+            (true, true) => return Err(FrameError::MaskedServerFrame),
+            _ => (),
+        }
+        if frame.rsv2() || frame.rsv3() {
+            return Err(FrameError::ReservedBitsSet)
+        }
+        if frame.rsv1() && (!deflate || frame.opcode().is_control() || frame.opcode() == Opcode::Continuation) {
+            return Err(FrameError::ReservedBitsSet)
+        }
+        if frame.opcode().is_control() {
+            if !frame.fin() {
+                return Err(FrameError::FragmentedControlFrame)
+            }
+            if frame.payload_len() > MAX_CONTROL_PAYLOAD_SIZE {
+                return Err(FrameError::ControlFrameTooLarge)
+            }
+            return Ok(Some(frame))
+        }
+
+        let (opcode, compressed, mut payload_data) = match (frame.opcode(), message.take()) {
+            (Opcode::Continuation, None) => return Err(FrameError::UnexpectedContinuation),
+            (Opcode::Continuation, Some(v)) => v,
+            (_, Some(_)) => return Err(FrameError::ExpectedContinuation),
+            (_, None) if frame.fin() && frame.payload_len() <= max_message_size => return Ok(Some(frame)),
+            (opcode, None) => (opcode, frame.rsv1(), vec![]),
+        };
+        if payload_data.len() + frame.payload_len() > max_message_size {
+            return Err(FrameError::MessageTooLarge)
+        }
+        payload_data.extend(frame.payload_data());
+
+        if frame.fin() {
+            Ok(Some(Frame::new(opcode, Some(payload_data)).with_rsv1(compressed)))
+        } else {
+            *message = Some((opcode, compressed, payload_data));
+            Ok(None)
+        }
+    }
+
+    // Writes queued frames until shut down. The shutdown may carry the last bytes to send
+    // in place of whatever is still queued.
+    async fn write(
+        mut writer: OwnedWriteHalf,
+        mut receiver: Receiver<Vec<u8>>,
+        mut shutdown: oneshot::Receiver<Option<Vec<u8>>>,
+    ) {
+        let close_bytes = loop {
+            let bytes = tokio::select! {
+                biased;
+                result = &mut shutdown => break result.ok().flatten(),
+                Some(bytes) = receiver.recv() => bytes,
+            };
+            if writer.write_all(&bytes).await.is_err() {
+                return
+            }
+        };
+        match close_bytes {
+            Some(bytes) => {
+                if writer.write_all(&bytes).await.is_err() {
+                    return
+                }
+            },
+            // Flush what was queued before the shutdown, such as a close frame
+            None => while let Ok(bytes) = receiver.try_recv() {
+                if writer.write_all(&bytes).await.is_err() {
+                    return
+                }
+            },
+        }
+        let _ = writer.shutdown().await;
+    }
+
+    // Answers the opening handshake and returns what it agreed on, or None if the request
+    // was rejected
+    fn opening_handshake(sender: &WebSocketSender, config: &WebSocketConfig, request: &[u8]) -> Option<Negotiation> {
+        let result = HandshakeRequest::parse(request).and_then(|request| {
+            let sec_websocket_accept = request.validate(&config.routes)?;
+            Ok((sec_websocket_accept, request.negotiate(&config.protocols, config.permessage_deflate)))
+        });
+        let (sec_websocket_accept, negotiation) = match result {
+            Ok(v) => v,
+            Err(e) => {
+                let _ = sender.send_bytes(e.to_response().into_bytes());
+                return None
+            },
+        };
+
+        let response = format!("{}\r\n{}\r\n{}\r\n{}{}\r\n{}\r\n",
+            "HTTP/1.1 101 Switching Protocols",
+            "Upgrade: websocket",
+            "Connection: Upgrade",
+            "Sec-WebSocket-Accept: ", sec_websocket_accept,
+            negotiation.response_headers(),
+        );
+        let _ = sender.send_bytes(response.into_bytes());
+
+        Some(negotiation)
+    }
+
+    async fn websocket_connection(operations: &HashMap<Opcode, Operation>, context: WebSocketContext) -> ConnectionPhase {
+        let sender = context.sender.clone();
+        match context.frame.opcode() {
+            Opcode::Text | Opcode::Binary => {
+                let func = match operations.get(&context.frame.opcode()) {
+                    Some(v) => v,
+                    None => return ConnectionPhase::Open,
+                };
+                match func(context).await {
+                    OperationResult::Continue => (),
+                    OperationResult::Reply(frame) => { let _ = sender.send(frame); },
+                    OperationResult::Close(code, reason) => { let _ = sender.close(code, &reason); },
+                };
+                ConnectionPhase::Open
+            },
+            Opcode::Close => {
+                // A close we started is complete once the peer answers; otherwise echo its code
+                if !sender.is_closing() {
+                    let frame = match context.frame.close_status() {
+                        Ok(Some((code, _))) => Frame::close(code, ""),
+                        Ok(None) => Frame::new(Opcode::Close, None),
+                        Err(e) => Frame::close(CloseCode::ProtocolError, &e.to_string()),
+                    };
+                    let _ = sender.close_with(frame);
+                }
+                ConnectionPhase::Closed
+            },
+            Opcode::Ping => {
+                let _ = sender.send(Frame::new(Opcode::Pong, Some(context.frame.payload_data())));
+                ConnectionPhase::Open
+            },
+            _ => ConnectionPhase::Open,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // As received by a server
+    fn reassemble(frame: Frame) -> Result<Option<Frame>, FrameError> {
+        Connection::reassemble(&mut None, frame.masked([1, 2, 3, 4]), false, false, usize::MAX)
+    }
+
+    #[test]
+    fn full_queue_fails_the_connection() {
+        let (sender, mut receiver) = mpsc::channel(1);
+        let sender = WebSocketSender {
+            sender,
+            is_client: false,
+            max_frame_size: None,
+            deflate: false,
+            closing: Arc::new(watch::Sender::new(false)),
+            overflowed: Arc::new(AtomicBool::new(false)),
+        };
+        assert!(sender.send_text("first".to_string()).is_ok());
+        assert!(!sender.is_closing());
+
+        assert_eq!(sender.send_text("second".to_string()), Err("Outgoing queue is full".to_string()));
+        assert!(sender.overflowed.load(Ordering::SeqCst));
+        assert!(sender.is_closing());
+
+        // Nothing more is queued once the connection is failing
+        receiver.try_recv().unwrap();
+        assert_eq!(sender.send_text("third".to_string()), Err("Connection is closed".to_string()));
+    }
+
+    #[test]
+    fn accepts_control_frames_up_to_125_bytes() {
+        for opcode in [Opcode::Ping, Opcode::Pong, Opcode::Close] {
+            let frame = reassemble(Frame::new(opcode, Some(vec![0; MAX_CONTROL_PAYLOAD_SIZE]))).ok().flatten().unwrap();
+            assert_eq!(frame.payload_len(), MAX_CONTROL_PAYLOAD_SIZE);
+        }
+    }
+
+    #[test]
+    fn rejects_oversize_control_frames() {
+        for opcode in [Opcode::Ping, Opcode::Pong, Opcode::Close] {
+            let error = reassemble(Frame::new(opcode, Some(vec![0; MAX_CONTROL_PAYLOAD_SIZE + 1]))).err().unwrap();
+            assert_eq!(error, FrameError::ControlFrameTooLarge);
+            assert_eq!(error.close_code(), CloseCode::ProtocolError);
+        }
+    }
+
+    #[test]
+    fn server_fails_on_unmasked_frames() {
+        for opcode in [Opcode::Text, Opcode::Ping] {
+            let error = Connection::reassemble(&mut None, Frame::new(opcode, None), false, false, usize::MAX).err().unwrap();
+            assert_eq!(error, FrameError::UnmaskedClientFrame);
+            assert_eq!(error.close_code(), CloseCode::ProtocolError);
+        }
+    }
+
+    #[test]
+    fn client_fails_on_masked_frames() {
+        for opcode in [Opcode::Text, Opcode::Ping] {
+            let frame = Frame::new(opcode, None).masked([1, 2, 3, 4]);
+            let error = Connection::reassemble(&mut None, frame, false, true, usize::MAX).err().unwrap();
+            assert_eq!(error, FrameError::MaskedServerFrame);
+            assert_eq!(error.close_code(), CloseCode::ProtocolError);
+        }
+        let frame = Connection::reassemble(&mut None, Frame::new(Opcode::Text, None), false, true, usize::MAX);
+        assert!(frame.ok().flatten().is_some());
+    }
+
+    #[test]
+    fn inflates_fragmented_compressed_messages() {
+        let payload_data = b"a compressed message sent in several fragments".repeat(4);
+        let frame = Frame::new(Opcode::Text, Some(deflate::compress(&payload_data))).with_rsv1(true);
+        let mut decoder = FrameDecoder::new();
+        for fragment in frame.fragment(8).into_iter() {
+            decoder.extend(&fragment.to_bytes());
+        }
+        decoder.extend(&Frame::new(Opcode::Ping, None).to_bytes());
+
+        let mut message = None;
+        let mut decompressor = Some(Decompressor::new(true));
+        let mut next_frame = || Connection::next_frame(&mut decoder, &mut message, &mut decompressor, true, usize::MAX);
+        let frame = next_frame().ok().flatten().unwrap();
+        assert!(frame.opcode() == Opcode::Text);
+        assert_eq!(frame.payload_data(), payload_data);
+        assert!(next_frame().ok().flatten().is_some_and(|frame| frame.opcode() == Opcode::Ping));
+        assert!(next_frame().ok().flatten().is_none());
+    }
+}
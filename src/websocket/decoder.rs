@@ -0,0 +1,44 @@
+use crate::websocket::frame::{Frame, FrameError};
+
+pub const DEFAULT_MAX_PAYLOAD_SIZE: usize = 16 * 1024 * 1024;
+
+// Buffers bytes read from a stream until they form whole frames. A frame may arrive split
+// across several reads, and one read may hold several frames.
+pub struct FrameDecoder {
+    buffer: Vec<u8>,
+    max_payload_size: usize,
+}
+
+impl Default for FrameDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FrameDecoder {
+    pub fn new() -> Self {
+        Self::with_max_payload_size(DEFAULT_MAX_PAYLOAD_SIZE)
+    }
+
+    pub fn with_max_payload_size(max_payload_size: usize) -> Self {
+        FrameDecoder {
+            buffer: vec![],
+            max_payload_size,
+        }
+    }
+
+    pub fn extend(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    // Returns the next whole frame, or None until more bytes have been received
+    pub fn decode(&mut self) -> Result<Option<Frame>, FrameError> {
+        match Frame::decode(&self.buffer, self.max_payload_size)? {
+            Some((frame, size)) => {
+                self.buffer.drain(..size);
+                Ok(Some(frame))
+            },
+            None => Ok(None),
+        }
+    }
+}
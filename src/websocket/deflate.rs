@@ -0,0 +1,195 @@
+use std::io::Write;
+
+use flate2::{write::{DeflateDecoder, DeflateEncoder}, Compression};
+use crate::websocket::frame::FrameError;
+
+// The permessage-deflate extension (RFC 7692). We compress every message on its own (no
+// context takeover), so no compressor state is kept between messages.
+pub const PERMESSAGE_DEFLATE: &str = "permessage-deflate";
+
+// A sync flush ends with these bytes, which are left off the wire (RFC 7692 section 7.2.1)
+const TRAILER: [u8; 4] = [0x00, 0x00, 0xFF, 0xFF];
+
+pub fn offer() -> String {
+    format!("{}; server_no_context_takeover; client_no_context_takeover", PERMESSAGE_DEFLATE)
+}
+
+// Picks the first permessage-deflate offer in a client's Sec-WebSocket-Extensions header
+// that we can honour, and returns the value to answer with. The answer asks the client to
+// drop its context too.
+pub fn accept_offer(extensions: &str) -> Option<String> {
+    extensions.split(',')
+        .find(|offer| is_acceptable_offer(offer))
+        .map(|_| offer())
+}
+
+// Checks a server's Sec-WebSocket-Extensions header against our offer. Returns None when
+// permessage-deflate was declined, or whether the server keeps its context between messages.
+pub fn parse_answer(extensions: &str) -> Result<Option<bool>, String> {
+    let mut context_takeover = None;
+    for extension in extensions.split(',').filter(|v| !v.trim().is_empty()) {
+        let mut params = extension.split(';').map(|v| v.trim());
+        if params.next() != Some(PERMESSAGE_DEFLATE) || context_takeover.is_some() {
+            return Err(format!("Unexpected extension in handshake response: {}", extension.trim()))
+        }
+        let params = params.collect::<Vec<&str>>();
+        // We do not offer client_max_window_bits, so the server must not set it
+        if params.iter().any(|param| param.starts_with("client_max_window_bits")) {
+            return Err("Unexpected client_max_window_bits in handshake response".to_string())
+        }
+        context_takeover = Some(!params.contains(&"server_no_context_takeover"));
+    }
+    Ok(context_takeover)
+}
+
+fn is_acceptable_offer(offer: &str) -> bool {
+    let mut params = offer.split(';').map(|v| v.trim());
+    if params.next() != Some(PERMESSAGE_DEFLATE) {
+        return false
+    }
+
+    let mut names = vec![];
+    for param in params {
+        let (name, value) = match param.split_once('=') {
+            Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+            None => (param, None),
+        };
+        if names.contains(&name) {
+            return false
+        }
+        names.push(name);
+
+        let is_valid = match (name, value) {
+            ("server_no_context_takeover", None) => true,
+            ("client_no_context_takeover", None) => true,
+            // We always compress with a 32 KiB window, so a smaller one cannot be honoured
+            ("server_max_window_bits", Some(bits)) => bits == "15",
+            ("client_max_window_bits", None) => true,
+            ("client_max_window_bits", Some(bits)) => bits.parse::<u8>().is_ok_and(|v| (8..=15).contains(&v)),
+            _ => false,
+        };
+        if !is_valid {
+            return false
+        }
+    }
+    true
+}
+
+pub fn compress(payload: &[u8]) -> Vec<u8> {
+    let mut encoder = DeflateEncoder::new(vec![], Compression::default());
+    // Writing to a Vec cannot fail
+    encoder.write_all(payload).unwrap();
+    encoder.flush().unwrap();
+
+    let mut compressed = std::mem::take(encoder.get_mut());
+    if compressed.ends_with(&TRAILER) {
+        compressed.truncate(compressed.len() - TRAILER.len());
+    }
+    compressed
+}
+
+// Inflates the messages of one connection. When the peer keeps its compression context,
+// the inflate window has to carry over from one message to the next.
+pub struct Decompressor {
+    decoder: DeflateDecoder<Vec<u8>>,
+    context_takeover: bool,
+}
+
+impl Decompressor {
+    pub fn new(context_takeover: bool) -> Self {
+        Decompressor {
+            decoder: DeflateDecoder::new(vec![]),
+            context_takeover,
+        }
+    }
+
+    // Inflates in small steps, so that a message which inflates past `max_message_size`
+    // is caught early
+    pub fn decompress(&mut self, payload: &[u8], max_message_size: usize) -> Result<Vec<u8>, FrameError> {
+        if !self.context_takeover {
+            self.decoder = DeflateDecoder::new(vec![]);
+        }
+        for chunk in payload.chunks(1024).chain([&TRAILER[..]]) {
+            self.decoder.write_all(chunk).map_err(|_| FrameError::InvalidCompressedData)?;
+            if self.decoder.get_ref().len() > max_message_size {
+                return Err(FrameError::MessageTooLarge)
+            }
+        }
+        self.decoder.flush().map_err(|_| FrameError::InvalidCompressedData)?;
+        if self.decoder.get_ref().len() > max_message_size {
+            return Err(FrameError::MessageTooLarge)
+        }
+        Ok(std::mem::take(self.decoder.get_mut()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_offers_we_can_honour() {
+        assert_eq!(accept_offer("permessage-deflate"), Some(offer()));
+        assert_eq!(accept_offer("permessage-deflate; client_max_window_bits"), Some(offer()));
+        assert_eq!(accept_offer("permessage-deflate; client_max_window_bits=10; server_max_window_bits=15"), Some(offer()));
+        // The first offer asks for a window we do not compress with, so the second is taken
+        assert_eq!(accept_offer("permessage-deflate; server_max_window_bits=10, permessage-deflate"), Some(offer()));
+    }
+
+    #[test]
+    fn declines_offers_we_cannot_honour() {
+        assert_eq!(accept_offer("x-webkit-deflate-frame"), None);
+        assert_eq!(accept_offer("permessage-deflate; server_max_window_bits=10"), None);
+        assert_eq!(accept_offer("permessage-deflate; client_max_window_bits=16"), None);
+        assert_eq!(accept_offer("permessage-deflate; server_no_context_takeover; server_no_context_takeover"), None);
+        assert_eq!(accept_offer("permessage-deflate; unknown"), None);
+    }
+
+    #[test]
+    fn parses_answers() {
+        assert_eq!(parse_answer(""), Ok(None));
+        assert_eq!(parse_answer(&offer()), Ok(Some(false)));
+        assert_eq!(parse_answer("permessage-deflate"), Ok(Some(true)));
+        assert!(parse_answer("permessage-deflate, permessage-deflate").is_err());
+        assert!(parse_answer("x-webkit-deflate-frame").is_err());
+        assert!(parse_answer("permessage-deflate; client_max_window_bits=10").is_err());
+    }
+
+    #[test]
+    fn compressed_messages_round_trip() {
+        let mut decompressor = Decompressor::new(false);
+        for payload in [vec![], b"hello".to_vec(), b"a longer message ".repeat(1000)] {
+            let compressed = compress(&payload);
+            assert!(!compressed.ends_with(&TRAILER));
+            assert_eq!(decompressor.decompress(&compressed, payload.len()), Ok(payload));
+        }
+    }
+
+    #[test]
+    fn inflates_with_the_context_of_previous_messages() {
+        // A peer that keeps its context may refer back to the previous message
+        let mut encoder = DeflateEncoder::new(vec![], Compression::default());
+        let mut decompressor = Decompressor::new(true);
+        let payload = b"a message sent twice".to_vec();
+        for _ in 0..2 {
+            encoder.write_all(&payload).unwrap();
+            encoder.flush().unwrap();
+            let mut compressed = std::mem::take(encoder.get_mut());
+            compressed.truncate(compressed.len() - TRAILER.len());
+            assert_eq!(decompressor.decompress(&compressed, usize::MAX), Ok(payload.clone()));
+        }
+    }
+
+    #[test]
+    fn stops_inflating_past_the_max_message_size() {
+        let compressed = compress(&vec![0; 1 << 24]);
+        assert!(compressed.len() < 1 << 16);
+        assert_eq!(Decompressor::new(false).decompress(&compressed, 1 << 16), Err(FrameError::MessageTooLarge));
+        assert_eq!(Decompressor::new(false).decompress(&compressed, 1 << 24).map(|v| v.len()), Ok(1 << 24));
+    }
+
+    #[test]
+    fn rejects_invalid_compressed_data() {
+        assert_eq!(Decompressor::new(false).decompress(&[0xFF; 16], usize::MAX), Err(FrameError::InvalidCompressedData));
+    }
+}
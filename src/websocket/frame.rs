@@ -1,4 +1,63 @@
-use crate::websocket::opcode::Opcode;
+use std::fmt;
+use crate::websocket::{
+    close_code::CloseCode,
+    decoder::DEFAULT_MAX_PAYLOAD_SIZE,
+    opcode::Opcode,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FrameError {
+    InvalidOpcode(u8),
+    InvalidPayloadLength,
+    NonMinimalPayloadLength,
+    PayloadTooLarge,
+    MessageTooLarge,
+    Incomplete,
+    TrailingData,
+    FragmentedControlFrame,
+    ControlFrameTooLarge,
+    UnexpectedContinuation,
+    ExpectedContinuation,
+    InvalidClosePayload,
+    ReservedBitsSet,
+    InvalidCompressedData,
+    UnmaskedClientFrame,
+    MaskedServerFrame,
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FrameError::InvalidOpcode(opcode) => write!(f, "Invalid opcode: {:#x}", opcode),
+            FrameError::InvalidPayloadLength => write!(f, "Invalid payload length"),
+            FrameError::NonMinimalPayloadLength => write!(f, "Payload length is not minimally encoded"),
+            FrameError::PayloadTooLarge => write!(f, "Frame payload is too large"),
+            FrameError::MessageTooLarge => write!(f, "Message is too large"),
+            FrameError::Incomplete => write!(f, "Incomplete frame"),
+            FrameError::TrailingData => write!(f, "Trailing data after frame"),
+            FrameError::FragmentedControlFrame => write!(f, "Control frames must not be fragmented"),
+            FrameError::ControlFrameTooLarge => write!(f, "Control frame payload is longer than 125 bytes"),
+            FrameError::UnexpectedContinuation => write!(f, "Continuation frame without a message to continue"),
+            FrameError::ExpectedContinuation => write!(f, "New message before the previous one was finished"),
+            FrameError::InvalidClosePayload => write!(f, "Invalid close frame payload"),
+            FrameError::ReservedBitsSet => write!(f, "Reserved bits set without a negotiated extension"),
+            FrameError::InvalidCompressedData => write!(f, "Invalid compressed data"),
+            FrameError::UnmaskedClientFrame => write!(f, "Frames sent by a client must be masked"),
+            FrameError::MaskedServerFrame => write!(f, "Frames sent by a server must not be masked"),
+        }
+    }
+}
+
+impl FrameError {
+    // The status code to close the connection with
+    pub fn close_code(&self) -> CloseCode {
+        match self {
+            FrameError::PayloadTooLarge | FrameError::MessageTooLarge => CloseCode::MessageTooBig,
+            FrameError::InvalidCompressedData => CloseCode::InvalidPayload,
+            _ => CloseCode::ProtocolError,
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct Frame {
@@ -18,7 +77,7 @@ impl Frame {
     pub fn rsv1(&self) -> bool { self.rsv1 }
     pub fn rsv2(&self) -> bool { self.rsv2 }
     pub fn rsv3(&self) -> bool { self.rsv3 }
-    pub fn opcode(&self) -> Opcode { self.opcode.clone() }
+    pub fn opcode(&self) -> Opcode { self.opcode }
     pub fn mask(&self) -> bool { self.mask }
     pub fn payload_len(&self) -> usize { self.payload_len }
     pub fn masking_key(&self) -> Option<[u8; 4]> { self.masking_key }
@@ -31,7 +90,7 @@ impl Frame {
         };
 
         Frame {
-            fin: true, // Fragmentation is not support, so always 1
+            fin: true, // Use `fragment` to split the message into several frames
             rsv1: false,
             rsv2: false,
             rsv3: false,
@@ -43,6 +102,67 @@ impl Frame {
         }
     }
 
+    // The payload of a close frame is a status code followed by a UTF-8 reason. A control
+    // frame carries at most 125 bytes, so a longer reason is cut short.
+    pub fn close(code: CloseCode, reason: &str) -> Self {
+        let mut reason_len = reason.len().min(123);
+        while !reason.is_char_boundary(reason_len) {
+            reason_len -= 1;
+        }
+        let mut payload_data = u16::from(code).to_be_bytes().to_vec();
+        payload_data.extend_from_slice(&reason.as_bytes()[..reason_len]);
+        Frame::new(Opcode::Close, Some(payload_data))
+    }
+
+    // Returns the status code and reason of a close frame, or None if it has no status code
+    pub fn close_status(&self) -> Result<Option<(CloseCode, String)>, FrameError> {
+        match self.payload_data.len() {
+            0 => return Ok(None),
+            1 => return Err(FrameError::InvalidClosePayload),
+            _ => (),
+        };
+        let code = CloseCode::from(u16::from_be_bytes([self.payload_data[0], self.payload_data[1]]));
+        let reason = String::from_utf8(self.payload_data[2..].to_vec()).map_err(|_| FrameError::InvalidClosePayload)?;
+        if !code.is_valid() {
+            return Err(FrameError::InvalidClosePayload)
+        }
+        Ok(Some((code, reason)))
+    }
+
+    // RSV1 marks the first frame of a compressed message (RFC 7692 section 6)
+    pub fn with_rsv1(mut self, rsv1: bool) -> Self {
+        self.rsv1 = rsv1;
+        self
+    }
+
+    // Frames sent by a client must be masked (RFC 6455 section 5.3)
+    pub fn masked(mut self, masking_key: [u8; 4]) -> Self {
+        self.mask = true;
+        self.masking_key = Some(masking_key);
+        self
+    }
+
+    // Splits a data frame into frames carrying at most `max_frame_size` bytes of payload each.
+    // Control frames are never fragmented.
+    pub fn fragment(self, max_frame_size: usize) -> Vec<Frame> {
+        if self.opcode.is_control() || !self.fin || self.payload_len <= max_frame_size {
+            return vec![self]
+        }
+
+        let chunks = self.payload_data.chunks(max_frame_size.max(1)).collect::<Vec<&[u8]>>();
+        let last = chunks.len() - 1;
+        chunks.into_iter()
+            .enumerate()
+            .map(|(i, chunk)| {
+                let opcode = if i == 0 { self.opcode } else { Opcode::Continuation };
+                let mut frame = Frame::new(opcode, Some(chunk.to_vec()));
+                frame.fin = i == last;
+                frame.rsv1 = i == 0 && self.rsv1;
+                frame
+            })
+            .collect()
+    }
+
     pub fn to_bytes(self) -> Vec<u8> {
         let mut buffer = Vec::new();
         buffer.push(
@@ -57,13 +177,14 @@ impl Frame {
             buffer.push((self.mask as u8) << 7 | self.payload_len as u8);
         } else if self.payload_len < 65536 {
             buffer.push((self.mask as u8) << 7 | 126_u8);
+            buffer.extend_from_slice((self.payload_len as u16).to_be_bytes().as_ref());
         } else {
             buffer.push((self.mask as u8) << 7 | 127_u8);
             buffer.extend_from_slice((self.payload_len as u64).to_be_bytes().as_ref());
         }
 
         if self.mask {
-            buffer.extend(self.masking_key.unwrap().clone());
+            buffer.extend(self.masking_key.unwrap());
         }
 
         for (i, b) in self.payload_data.iter().enumerate() {
@@ -74,34 +195,64 @@ impl Frame {
             });
         }
 
-        return buffer
+        buffer
     }
-}
 
-impl From<&[u8]> for Frame {
-    fn from(buffer: &[u8]) -> Self {
+    // Decodes the frame at the start of `buffer`. Returns the frame and the number of bytes
+    // it took, or None when the buffer does not hold a whole frame yet. A frame declaring a
+    // payload over `max_payload_size` is rejected before it has been received.
+    pub fn decode(buffer: &[u8], max_payload_size: usize) -> Result<Option<(Frame, usize)>, FrameError> {
+        if buffer.len() < 2 {
+            return Ok(None)
+        }
         let fin = buffer[0] & 0x80 != 0x00;
         let rsv1 = buffer[0] & 0x40 != 0x00;
         let rsv2 = buffer[0] & 0x20 != 0x00;
         let rsv3 = buffer[0] & 0x10 != 0x00;
-        let opcode = Opcode::from(buffer[0]);
+        let opcode = Opcode::try_from(buffer[0])?;
 
         let mask = buffer[1] & 0x80 != 0;
+        // The extended lengths must use the shortest form (RFC 6455 section 5.2)
         let (payload_len, mut i) = match buffer[1] & 0x7F {
             0x7E => {
+                if buffer.len() < 4 {
+                    return Ok(None)
+                }
                 let mut payload_len = [0; 2];
                 payload_len.copy_from_slice(&buffer[2..4]);
-                (u16::from_be_bytes(payload_len) as usize, 4)
+                let payload_len = u16::from_be_bytes(payload_len) as u64;
+                if payload_len < 126 {
+                    return Err(FrameError::NonMinimalPayloadLength)
+                }
+                (payload_len, 4)
             }
             0x7F => {
+                if buffer.len() < 10 {
+                    return Ok(None)
+                }
                 let mut payload_len = [0; 8];
                 payload_len.copy_from_slice(&buffer[2..10]);
-                (usize::from_be_bytes(payload_len), 10)
+                let payload_len = u64::from_be_bytes(payload_len);
+                if payload_len < 65536 {
+                    return Err(FrameError::NonMinimalPayloadLength)
+                }
+                (payload_len, 10)
             }
-            n => (n as usize, 2)
+            n => (n as u64, 2)
+        };
+        // The most significant bit of a 64-bit length must be 0
+        let payload_len = match usize::try_from(payload_len) {
+            Ok(v) if payload_len >> 63 == 0 => v,
+            _ => return Err(FrameError::InvalidPayloadLength),
         };
+        if payload_len > max_payload_size {
+            return Err(FrameError::PayloadTooLarge)
+        }
 
         let masking_key = if mask {
+            if buffer.len() < i + 4 {
+                return Ok(None)
+            }
             let mut masking_key = [0; 4];
             masking_key.copy_from_slice(&buffer[i..i + 4]);
             i += 4;
@@ -109,17 +260,19 @@ impl From<&[u8]> for Frame {
         } else {
             None
         };
-        let payload_data: Vec<u8> = if mask {
-            buffer[i..i + payload_len]
+        if buffer.len() - i < payload_len {
+            return Ok(None)
+        }
+        let payload_data: Vec<u8> = match masking_key {
+            Some(masking_key) => buffer[i..i + payload_len]
                 .iter()
                 .enumerate()
-                .map(|(i, b)| b ^ masking_key.unwrap()[i % 4])
-                .collect()
-        } else {
-            buffer[i..i + payload_len].to_vec()
+                .map(|(i, b)| b ^ masking_key[i % 4])
+                .collect(),
+            None => buffer[i..i + payload_len].to_vec(),
         };
 
-        Frame {
+        let frame = Frame {
             fin,
             rsv1,
             rsv2,
@@ -129,6 +282,149 @@ impl From<&[u8]> for Frame {
             payload_len,
             masking_key,
             payload_data,
+        };
+        Ok(Some((frame, i + payload_len)))
+    }
+}
+
+// Decodes a buffer holding exactly one frame of at most DEFAULT_MAX_PAYLOAD_SIZE bytes
+impl TryFrom<&[u8]> for Frame {
+    type Error = FrameError;
+
+    fn try_from(buffer: &[u8]) -> Result<Frame, FrameError> {
+        match Frame::decode(buffer, DEFAULT_MAX_PAYLOAD_SIZE)? {
+            Some((frame, size)) if size == buffer.len() => Ok(frame),
+            Some(_) => Err(FrameError::TrailingData),
+            None => Err(FrameError::Incomplete),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Payload lengths around the boundaries of the 7-bit, 16-bit and 64-bit length forms
+    const PAYLOAD_LENS: [usize; 9] = [0, 1, 125, 126, 127, 1000, 65535, 65536, 70000];
+
+    fn payload(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 31 % 251) as u8).collect()
+    }
+
+    fn assert_round_trip(frame: Frame) {
+        let bytes = frame.clone().to_bytes();
+        let decoded = match Frame::try_from(&bytes[..]) {
+            Ok(v) => v,
+            Err(e) => panic!("{} byte payload failed to decode: {}", frame.payload_len(), e),
+        };
+        assert!(decoded.fin() == frame.fin());
+        assert!(decoded.rsv1() == frame.rsv1());
+        assert!(decoded.opcode() == frame.opcode());
+        assert_eq!(decoded.mask(), frame.mask());
+        assert_eq!(decoded.masking_key(), frame.masking_key());
+        assert_eq!(decoded.payload_len(), frame.payload_len());
+        assert_eq!(decoded.payload_data(), frame.payload_data());
+    }
+
+    // The length field of an unmasked frame: the 7-bit length and any extended length bytes
+    fn length_field(bytes: &[u8]) -> (u8, &[u8]) {
+        let len = bytes[1] & 0x7F;
+        let extended = match len {
+            126 => &bytes[2..4],
+            127 => &bytes[2..10],
+            _ => &bytes[2..2],
+        };
+        (len, extended)
+    }
+
+    #[test]
+    fn unmasked_frames_round_trip() {
+        for len in PAYLOAD_LENS {
+            assert_round_trip(Frame::new(Opcode::Binary, Some(payload(len))));
+        }
+    }
+
+    #[test]
+    fn masked_frames_round_trip() {
+        for len in PAYLOAD_LENS {
+            assert_round_trip(Frame::new(Opcode::Text, Some(payload(len))).masked([0x12, 0x34, 0x56, 0x78]));
+        }
+    }
+
+    #[test]
+    fn masked_payload_is_not_sent_in_the_clear() {
+        let bytes = Frame::new(Opcode::Binary, Some(payload(8))).masked([0xFF; 4]).to_bytes();
+        assert_eq!(bytes[1], 0x80 | 8);
+        assert_eq!(&bytes[2..6], &[0xFF; 4]);
+        assert_ne!(&bytes[6..], &payload(8)[..]);
+    }
+
+    #[test]
+    fn encodes_7_bit_length() {
+        for len in [0, 1, 125] {
+            let bytes = Frame::new(Opcode::Binary, Some(payload(len))).to_bytes();
+            assert_eq!(length_field(&bytes), (len as u8, &[][..]));
+            assert_eq!(bytes.len(), 2 + len);
+        }
+    }
+
+    #[test]
+    fn encodes_16_bit_length() {
+        for len in [126, 127, 65535] {
+            let bytes = Frame::new(Opcode::Binary, Some(payload(len))).to_bytes();
+            assert_eq!(length_field(&bytes), (126, &(len as u16).to_be_bytes()[..]));
+            assert_eq!(bytes.len(), 4 + len);
+        }
+    }
+
+    #[test]
+    fn encodes_64_bit_length() {
+        for len in [65536, 70000] {
+            let bytes = Frame::new(Opcode::Binary, Some(payload(len))).to_bytes();
+            assert_eq!(length_field(&bytes), (127, &(len as u64).to_be_bytes()[..]));
+            assert_eq!(bytes.len(), 10 + len);
+        }
+    }
+
+    #[test]
+    fn rejects_non_minimal_16_bit_length() {
+        let mut bytes = vec![0x82, 126];
+        bytes.extend_from_slice(&125_u16.to_be_bytes());
+        bytes.extend(payload(125));
+        assert_eq!(Frame::try_from(&bytes[..]).err(), Some(FrameError::NonMinimalPayloadLength));
+    }
+
+    #[test]
+    fn rejects_non_minimal_64_bit_length() {
+        let mut bytes = vec![0x82, 127];
+        bytes.extend_from_slice(&65535_u64.to_be_bytes());
+        bytes.extend(payload(65535));
+        assert_eq!(Frame::try_from(&bytes[..]).err(), Some(FrameError::NonMinimalPayloadLength));
+    }
+
+    #[test]
+    fn rejects_length_with_most_significant_bit_set() {
+        let mut bytes = vec![0x82, 127];
+        bytes.extend_from_slice(&(1_u64 << 63).to_be_bytes());
+        assert_eq!(Frame::try_from(&bytes[..]).err(), Some(FrameError::InvalidPayloadLength));
+    }
+
+    #[test]
+    fn rejects_oversize_payload_before_it_arrives() {
+        let mut bytes = vec![0x82, 127];
+        bytes.extend_from_slice(&(DEFAULT_MAX_PAYLOAD_SIZE as u64 + 1).to_be_bytes());
+        assert_eq!(Frame::try_from(&bytes[..]).err(), Some(FrameError::PayloadTooLarge));
+        assert_eq!(Frame::decode(&bytes, 65536).err(), Some(FrameError::PayloadTooLarge));
+    }
+
+    #[test]
+    fn rejects_incomplete_and_trailing_bytes() {
+        let bytes = Frame::new(Opcode::Binary, Some(payload(200))).to_bytes();
+        assert_eq!(Frame::try_from(&bytes[..bytes.len() - 1]).err(), Some(FrameError::Incomplete));
+        assert_eq!(Frame::try_from(&bytes[..3]).err(), Some(FrameError::Incomplete));
+
+        let mut bytes = bytes;
+        bytes.push(0);
+        assert_eq!(Frame::try_from(&bytes[..]).err(), Some(FrameError::TrailingData));
+    }
+}
@@ -0,0 +1,79 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use crate::websocket::{
+    close_code::CloseCode,
+    connection::WebSocketSender,
+    frame::Frame,
+};
+
+pub type ConnectionId = u64;
+
+struct OpenConnection {
+    peer_addr: String,
+    sender: WebSocketSender,
+}
+
+// Reaches every open connection of a server, both the ones it accepted and the ones it
+// dialed. Clones share the same connections.
+#[derive(Clone, Default)]
+pub struct WebSocketHandle {
+    next_id: Arc<AtomicU64>,
+    connections: Arc<Mutex<HashMap<ConnectionId, OpenConnection>>>,
+}
+
+impl WebSocketHandle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Open connections with their peer addresses, oldest first
+    pub fn connections(&self) -> Vec<(ConnectionId, String)> {
+        let mut connections = self.connections.lock().unwrap()
+            .iter()
+            .map(|(id, connection)| (*id, connection.peer_addr.clone()))
+            .collect::<Vec<(ConnectionId, String)>>();
+        connections.sort();
+        connections
+    }
+
+    pub fn send_to(&self, connection_id: ConnectionId, frame: Frame) -> Result<(), String> {
+        match self.connections.lock().unwrap().get(&connection_id) {
+            Some(connection) => connection.sender.send(frame),
+            None => Err(format!("No connection with id {}", connection_id)),
+        }
+    }
+
+    // Sends to every open connection and returns how many accepted the frame
+    pub fn broadcast(&self, frame: Frame) -> usize {
+        self.connections.lock().unwrap()
+            .values()
+            .filter(|connection| connection.sender.send(frame.clone()).is_ok())
+            .count()
+    }
+
+    pub fn close(&self, connection_id: ConnectionId, code: CloseCode, reason: &str) -> Result<(), String> {
+        match self.connections.lock().unwrap().get(&connection_id) {
+            Some(connection) => connection.sender.close(code, reason),
+            None => Err(format!("No connection with id {}", connection_id)),
+        }
+    }
+
+    pub fn next_id(&self) -> ConnectionId {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    // Called by a connection once its opening handshake is done, and when it ends
+    pub fn register(&self, connection_id: ConnectionId, peer_addr: String, sender: WebSocketSender) {
+        self.connections.lock().unwrap().insert(connection_id, OpenConnection { peer_addr, sender });
+    }
+
+    pub fn unregister(&self, connection_id: ConnectionId) {
+        self.connections.lock().unwrap().remove(&connection_id);
+    }
+}
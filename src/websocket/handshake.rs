@@ -0,0 +1,286 @@
+use std::collections::HashMap;
+
+use base64::{engine::general_purpose, Engine as _};
+use sha1::{Digest, Sha1};
+use crate::websocket::{WEBSOCKET_GUID, deflate};
+
+// Requests larger than this are rejected before the end of the headers is found
+pub const MAX_HANDSHAKE_SIZE: usize = 8192;
+
+#[derive(Debug, PartialEq)]
+pub enum HandshakeError {
+    BadRequest(String),
+    NotFound,
+    UpgradeRequired,
+}
+
+impl HandshakeError {
+    pub fn to_response(&self) -> String {
+        match self {
+            HandshakeError::BadRequest(reason) => format!("{}\r\n{}\r\n{}{}\r\n\r\n{}",
+                "HTTP/1.1 400 Bad Request",
+                "Connection: close",
+                "Content-Length: ", reason.len(),
+                reason,
+            ),
+            HandshakeError::NotFound => format!("{}\r\n{}\r\n{}\r\n\r\n",
+                "HTTP/1.1 404 Not Found",
+                "Connection: close",
+                "Content-Length: 0",
+            ),
+            // Tells the client which version we speak (RFC 6455 section 4.4)
+            HandshakeError::UpgradeRequired => format!("{}\r\n{}\r\n{}\r\n{}\r\n\r\n",
+                "HTTP/1.1 426 Upgrade Required",
+                "Sec-WebSocket-Version: 13",
+                "Connection: close",
+                "Content-Length: 0",
+            ),
+        }
+    }
+}
+
+// What the opening handshake agreed on
+#[derive(Clone, Default)]
+pub struct Negotiation {
+    pub protocol: Option<String>,
+    pub deflate: bool,
+    // Whether the peer compresses each message with the context of the previous ones
+    pub peer_context_takeover: bool,
+}
+
+impl Negotiation {
+    // Header lines to add to the 101 response
+    pub fn response_headers(&self) -> String {
+        let mut headers = String::new();
+        if let Some(protocol) = &self.protocol {
+            headers.push_str(&format!("Sec-WebSocket-Protocol: {}\r\n", protocol));
+        }
+        if self.deflate {
+            headers.push_str(&format!("Sec-WebSocket-Extensions: {}\r\n", deflate::offer()));
+        }
+        headers
+    }
+}
+
+pub struct HandshakeRequest {
+    method: String,
+    path: String,
+    headers: HashMap<String, String>,
+}
+
+impl HandshakeRequest {
+    pub fn method(&self) -> String { self.method.clone() }
+    pub fn path(&self) -> String { self.path.clone() }
+    pub fn header(&self, name: &str) -> Option<String> { self.headers.get(name).cloned() }
+
+    // The length of the request at the start of `bytes`, once its headers have all arrived.
+    // Whatever follows belongs to the frames sent right after it.
+    pub fn len(bytes: &[u8]) -> Result<Option<usize>, HandshakeError> {
+        match bytes.windows(4).position(|v| v == b"\r\n\r\n") {
+            Some(i) => Ok(Some(i + 4)),
+            None if bytes.len() > MAX_HANDSHAKE_SIZE => Err(HandshakeError::BadRequest("Request is too large".to_string())),
+            None => Ok(None),
+        }
+    }
+
+    pub fn parse(request: &[u8]) -> Result<Self, HandshakeError> {
+        let request = std::str::from_utf8(request)
+            .map_err(|_| HandshakeError::BadRequest("Request is not valid UTF-8".to_string()))?;
+        let mut lines = request.split("\r\n");
+
+        let request_line = lines.next().unwrap_or("");
+        let (method, path, protocol_version) = match request_line.split(' ').collect::<Vec<&str>>()[..] {
+            [method, path, protocol_version] => (method, path, protocol_version),
+            _ => return Err(HandshakeError::BadRequest("Malformed request line".to_string())),
+        };
+        if protocol_version != "HTTP/1.1" {
+            return Err(HandshakeError::BadRequest("HTTP/1.1 is required".to_string()))
+        }
+
+        let headers = parse_headers(lines)
+            .ok_or(HandshakeError::BadRequest("Malformed header".to_string()))?;
+        Ok(HandshakeRequest {
+            method: method.to_string(),
+            // The query string plays no part in routing
+            path: path.split('?').next().unwrap_or(path).to_string(),
+            headers,
+        })
+    }
+
+    // Checks the request against RFC 6455 section 4.2.1 and returns the Sec-WebSocket-Accept
+    // value to answer with. An empty route list accepts any path.
+    pub fn validate(&self, routes: &[String]) -> Result<String, HandshakeError> {
+        if self.method != "GET" {
+            return Err(HandshakeError::BadRequest("Method must be GET".to_string()))
+        }
+        if !routes.is_empty() && !routes.contains(&self.path) {
+            return Err(HandshakeError::NotFound)
+        }
+        if !self.headers.contains_key("host") {
+            return Err(HandshakeError::BadRequest("Missing Host header".to_string()))
+        }
+        if !has_token(&self.headers, "upgrade", "websocket") {
+            return Err(HandshakeError::UpgradeRequired)
+        }
+        if !has_token(&self.headers, "connection", "upgrade") {
+            return Err(HandshakeError::BadRequest("Connection header must contain Upgrade".to_string()))
+        }
+        if self.headers.get("sec-websocket-version").map(|v| v.as_str()) != Some("13") {
+            return Err(HandshakeError::UpgradeRequired)
+        }
+
+        let sec_websocket_key = self.headers.get("sec-websocket-key")
+            .ok_or(HandshakeError::BadRequest("Missing Sec-WebSocket-Key header".to_string()))?;
+        // The key is 16 random bytes, base64-encoded
+        match general_purpose::STANDARD.decode(sec_websocket_key) {
+            Ok(key) if key.len() == 16 => (),
+            _ => return Err(HandshakeError::BadRequest("Invalid Sec-WebSocket-Key header".to_string())),
+        };
+        Ok(accept_key(sec_websocket_key))
+    }
+
+    // Chooses the first subprotocol the client asked for that we support, and accepts
+    // permessage-deflate when it is enabled and offered
+    pub fn negotiate(&self, protocols: &[String], permessage_deflate: bool) -> Negotiation {
+        let protocol = self.headers.get("sec-websocket-protocol").and_then(|requested| {
+            requested.split(',')
+                .map(|v| v.trim().to_string())
+                .find(|v| protocols.contains(v))
+        });
+        let deflate = permessage_deflate && self.headers.get("sec-websocket-extensions")
+            .and_then(|extensions| deflate::accept_offer(extensions))
+            .is_some();
+        Negotiation {
+            protocol,
+            deflate,
+            // Our answer asks the client to drop its context
+            peer_context_takeover: false,
+        }
+    }
+}
+
+pub fn accept_key(sec_websocket_key: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(format!("{}{}", sec_websocket_key, WEBSOCKET_GUID));
+    general_purpose::STANDARD.encode(hasher.finalize())
+}
+
+// Parses header lines up to the first empty line. Names are lowercased, and repeated
+// headers are joined with commas as HTTP allows. Returns None on a line without a colon.
+pub fn parse_headers<'a>(lines: impl Iterator<Item = &'a str>) -> Option<HashMap<String, String>> {
+    let mut headers: HashMap<String, String> = HashMap::new();
+    for line in lines {
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line.split_once(':')?;
+        let name = name.trim().to_lowercase();
+        let value = value.trim();
+        headers.entry(name)
+            .and_modify(|v| { v.push_str(", "); v.push_str(value); })
+            .or_insert(value.to_string());
+    }
+    Some(headers)
+}
+
+// Whether a comma-separated header contains `token`, ignoring case
+pub fn has_token(headers: &HashMap<String, String>, name: &str, token: &str) -> bool {
+    headers.get(name).is_some_and(|value| {
+        value.split(',').any(|v| v.trim().eq_ignore_ascii_case(token))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "dGhlIHNhbXBsZSBub25jZQ==";
+
+    fn request(request_line: &str, headers: &[&str]) -> Vec<u8> {
+        format!("{}\r\n{}\r\n", request_line, headers.iter().map(|v| format!("{}\r\n", v)).collect::<String>())
+            .into_bytes()
+    }
+
+    // The headers of a valid opening handshake
+    fn headers() -> Vec<String> {
+        vec![
+            "Host: a:3000".to_string(),
+            "Upgrade: websocket".to_string(),
+            "Connection: keep-alive, Upgrade".to_string(),
+            format!("Sec-WebSocket-Key: {}", KEY),
+            "Sec-WebSocket-Version: 13".to_string(),
+        ]
+    }
+
+    fn headers_without(name: &str) -> Vec<String> {
+        headers().into_iter()
+            .filter(|v| !v.to_lowercase().starts_with(&format!("{}:", name)))
+            .collect()
+    }
+
+    fn validate(request_line: &str, headers: &[String]) -> Result<String, HandshakeError> {
+        let headers = headers.iter().map(|v| v.as_str()).collect::<Vec<&str>>();
+        HandshakeRequest::parse(&request(request_line, &headers))?.validate(&["/".to_string()])
+    }
+
+    #[test]
+    fn accepts_a_host_with_a_port() {
+        let request = HandshakeRequest::parse(&request("GET /?peer=1 HTTP/1.1", &["Host: a:3000"])).unwrap();
+        assert_eq!(request.header("host"), Some("a:3000".to_string()));
+        assert_eq!(request.path(), "/");
+
+        // The accept value from RFC 6455 section 1.3
+        assert_eq!(validate("GET / HTTP/1.1", &headers()), Ok("s3pPLMBiTxaQ9kYGzzhZRbK+xOo=".to_string()));
+    }
+
+    #[test]
+    fn waits_for_a_request_split_across_reads() {
+        let mut bytes = request("GET / HTTP/1.1", &["Host: a:3000", "Upgrade: websocket"]);
+        let request_len = bytes.len();
+        // A frame sent right after the request
+        bytes.extend([0x81, 0x00]);
+
+        for split in 0..request_len {
+            assert_eq!(HandshakeRequest::len(&bytes[..split]), Ok(None));
+        }
+        assert_eq!(HandshakeRequest::len(&bytes[..request_len]), Ok(Some(request_len)));
+        assert_eq!(HandshakeRequest::len(&bytes), Ok(Some(request_len)));
+        assert_eq!(
+            HandshakeRequest::len(&[b'a'; MAX_HANDSHAKE_SIZE + 1]),
+            Err(HandshakeError::BadRequest("Request is too large".to_string())),
+        );
+    }
+
+    #[test]
+    fn rejects_a_missing_key_with_400() {
+        let error = validate("GET / HTTP/1.1", &headers_without("sec-websocket-key")).err().unwrap();
+        assert_eq!(error, HandshakeError::BadRequest("Missing Sec-WebSocket-Key header".to_string()));
+        assert!(error.to_response().starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    }
+
+    #[test]
+    fn rejects_other_versions_with_426() {
+        let mut headers = headers_without("sec-websocket-version");
+        headers.push("Sec-WebSocket-Version: 8".to_string());
+        let error = validate("GET / HTTP/1.1", &headers).err().unwrap();
+        assert_eq!(error, HandshakeError::UpgradeRequired);
+        let response = error.to_response();
+        assert!(response.starts_with("HTTP/1.1 426 Upgrade Required\r\n"));
+        assert!(response.contains("\r\nSec-WebSocket-Version: 13\r\n"));
+    }
+
+    #[test]
+    fn rejects_methods_other_than_get() {
+        assert_eq!(
+            validate("POST / HTTP/1.1", &headers()),
+            Err(HandshakeError::BadRequest("Method must be GET".to_string())),
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_routes_with_404() {
+        let error = validate("GET /unknown HTTP/1.1", &headers()).err().unwrap();
+        assert_eq!(error, HandshakeError::NotFound);
+        assert!(error.to_response().starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+}
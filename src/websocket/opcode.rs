@@ -1,3 +1,5 @@
+use crate::websocket::frame::FrameError;
+
 #[derive(Copy, Clone, Eq, PartialEq, Hash)]
 pub enum Opcode {
     Continuation, // %x0
@@ -8,16 +10,25 @@ pub enum Opcode {
     Pong,         // %xA
 }
 
-impl From<u8> for Opcode {
-    fn from(byte: u8) -> Opcode {
+impl Opcode {
+    // Control frames may be interleaved with the fragments of a message
+    pub fn is_control(&self) -> bool {
+        matches!(self, Opcode::Close | Opcode::Ping | Opcode::Pong)
+    }
+}
+
+impl TryFrom<u8> for Opcode {
+    type Error = FrameError;
+
+    fn try_from(byte: u8) -> Result<Opcode, FrameError> {
         match byte & 0x0F {
-            0x0 => Opcode::Continuation,
-            0x1 => Opcode::Text,
-            0x2 => Opcode::Binary,
-            0x8 => Opcode::Close,
-            0x9 => Opcode::Ping,
-            0xA => Opcode::Pong,
-            _ => panic!("Invalid opcode"),
+            0x0 => Ok(Opcode::Continuation),
+            0x1 => Ok(Opcode::Text),
+            0x2 => Ok(Opcode::Binary),
+            0x8 => Ok(Opcode::Close),
+            0x9 => Ok(Opcode::Ping),
+            0xA => Ok(Opcode::Pong),
+            n => Err(FrameError::InvalidOpcode(n)),
         }
     }
 }